@@ -0,0 +1,430 @@
+use crate::runner::{self, Kind, Runner};
+use crate::shell::Mode;
+
+pub const HELP: &str = "\
+Runs the code blocks in Markdown files and checks that they behave as expected
+
+Usage: md-command-check [COMMAND] [OPTIONS] [--] <PATHS>...
+
+Paths can be Markdown files, directories or glob patterns such as 'docs/**/*.md'. Defaults are read
+from md-command-check.toml, which is looked up from the current directory to the repository root.
+
+Commands:
+  run      Runs every code block (default)
+  list     Lists the code blocks which would be run
+  check    Checks that files and their directives are valid, without running anything
+  export   Writes the code blocks which would be run to stdout as a shell script
+
+Options:
+      --debug                 Shows the output of every code block
+      --pty                   Runs every code block inside of a pseudo-terminal
+      --timeout <DURATION>    Default timeout for each code block, such as 30s or 2m
+      --file-timeout <DURATION>
+                              Limit on how long each file is allowed to run for
+      --keep-going            Keeps running code blocks after a failure
+      --fail-fast <all|file|never>
+                              Stops the entire run or skips to the next file after a failure
+      --format <pretty|tap|jsonl>
+                              How results are written to stdout
+      --include <GLOB>        Only runs files which match, can be repeated
+      --exclude <GLOB>        Skips files which match, can be repeated
+  -j, --jobs <N>              Runs files in parallel, each with its own shells and variables
+      --chain <PATHS>         Comma-separated files which run in order and share their shells and
+                              variables when running in parallel, can be repeated
+      --report junit=<PATH>   Writes a JUnit XML report
+      --progress <auto|plain|tty>
+                              Whether code blocks are redrawn as they run
+      --color <auto|never|always>
+                              Whether to use colors
+      --shell <LANG=PROGRAM>  Runs code blocks of a language in a long-running shell, can be repeated
+      --script <LANG=COMMAND> Runs code blocks of a language as a fresh script, can be repeated
+      --sandbox               Runs code blocks in a temporary directory, which is removed afterwards
+      --seed <PATH>           Copies a file or directory into the sandbox, can be repeated
+      --keep-sandbox          Keeps the sandbox once done
+      --config <PATH>         Reads defaults from a configuration file
+  -h, --help                  Prints help
+  -V, --version               Prints version";
+
+/// Options which apply to every file.
+#[derive(Clone)]
+pub struct Options {
+    pub command: Command,
+    /// Files to run, which are given as paths until they are resolved.
+    pub files: Vec<String>,
+    /// Glob patterns of the files to run.
+    pub include: Vec<String>,
+    /// Glob patterns of the files to skip.
+    pub exclude: Vec<String>,
+    /// Number of files run at the same time, if set. Files then no longer share their shells and
+    /// variables unless they are chained.
+    pub jobs: Option<usize>,
+    /// Files which depend on each other, and which are run in order.
+    pub chains: Vec<Vec<String>>,
+    pub debug: bool,
+    pub timeout: Option<std::time::Duration>,
+    pub file_timeout: Option<std::time::Duration>,
+    pub mode: Mode,
+    pub fail_fast: FailFast,
+    /// Path to write a JUnit XML report to.
+    pub junit: Option<String>,
+    pub format: Format,
+    /// Whether code blocks are redrawn as they run, if set. Defaults to whether output is written
+    /// to a terminal.
+    pub progress: Option<bool>,
+    /// Whether to use colors, if set. Defaults to whether output is written to a terminal.
+    pub color: Option<bool>,
+    /// Whether code blocks are redrawn as they run, rather than drawn once they are done.
+    pub interactive: bool,
+    /// Languages of the code blocks which are run, along with how they are run.
+    pub runners: Vec<Runner>,
+    /// Languages of the code blocks which are left out entirely, rather than reported as ignored.
+    pub ignore_languages: Vec<String>,
+    /// Environment variables set in the shell.
+    pub env: Vec<(String, String)>,
+    /// Whether code blocks are run in a temporary directory rather than in the current directory.
+    pub sandbox: bool,
+    /// Paths copied into the sandbox before running anything.
+    pub seed: Vec<String>,
+    /// Whether the sandbox is kept once done, to look into what code blocks left behind.
+    pub keep_sandbox: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            command: Command::Run,
+            files: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            jobs: None,
+            chains: Vec::new(),
+            debug: false,
+            timeout: None,
+            file_timeout: None,
+            mode: Mode::Pipe,
+            fail_fast: FailFast::All,
+            junit: None,
+            format: Format::Pretty,
+            progress: None,
+            color: None,
+            interactive: true,
+            runners: runner::defaults(),
+            ignore_languages: Vec::new(),
+            env: Vec::new(),
+            sandbox: false,
+            seed: Vec::new(),
+            keep_sandbox: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Command {
+    Run,
+    List,
+    Check,
+    Export,
+    Help,
+    Version,
+}
+
+/// How results are written to stdout.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Code blocks are drawn as they run.
+    Pretty,
+    /// Test Anything Protocol, with messages written to stderr.
+    Tap,
+    /// A JSON object per line for every step of the run, with messages written to stderr.
+    Jsonl,
+}
+
+/// What to skip once a code block has failed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FailFast {
+    /// Stops the entire run.
+    All,
+    /// Skips the rest of the file, moving on to the next one.
+    File,
+    /// Keeps running every code block.
+    Never,
+}
+
+/// Invalid command-line arguments.
+pub enum CliError {
+    /// An unknown option, along with the closest known option to help with typos.
+    Unknown {
+        option: String,
+        suggestion: Option<&'static str>,
+    },
+    /// An option which expects a value was not given one.
+    Missing {
+        option: String,
+    },
+    /// An option which does not expect a value was given one.
+    Unexpected {
+        option: String,
+    },
+    Value {
+        option: String,
+        value: String,
+        expected: String,
+    },
+    Duration {
+        option: String,
+        value: String,
+    },
+    NoFiles,
+}
+
+/// Options which do not take a value.
+const FLAGS: &[&str] = &[
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+    "--list",
+    "--debug",
+    "--pty",
+    "--keep-going",
+    "--sandbox",
+    "--keep-sandbox",
+];
+
+/// Options which take a value.
+const VALUES: &[&str] = &[
+    "--timeout",
+    "--file-timeout",
+    "--fail-fast",
+    "--format",
+    "--include",
+    "--exclude",
+    "-j",
+    "--jobs",
+    "--chain",
+    "--report",
+    "--progress",
+    "--color",
+    "--config",
+    "--shell",
+    "--script",
+    "--seed",
+];
+
+pub const FAIL_FAST: &[(&str, FailFast)] = &[
+    ("all", FailFast::All),
+    ("file", FailFast::File),
+    ("never", FailFast::Never),
+];
+
+pub const FORMAT: &[(&str, Format)] = &[
+    ("pretty", Format::Pretty),
+    ("tap", Format::Tap),
+    ("jsonl", Format::Jsonl),
+];
+
+pub const PROGRESS: &[(&str, Option<bool>)] = &[("auto", None), ("plain", Some(false)), ("tty", Some(true))];
+
+pub const COLOR: &[(&str, Option<bool>)] = &[("auto", None), ("never", Some(false)), ("always", Some(true))];
+
+/// Parses command-line arguments, without the program name, on top of the defaults read from the
+/// configuration file.
+///
+/// The first argument may be a command, defaulting to `run`. Options can be placed anywhere and take
+/// their value either as the next argument or after a `=`, as in `--timeout=30s`. Every argument
+/// after `--` is a file. Files, patterns and chains replace those of the configuration file rather
+/// than adding to them.
+pub fn parse(args: impl IntoIterator<Item = String>, mut options: Options) -> Result<Options, CliError> {
+    let mut args = args.into_iter().peekable();
+
+    let command = match args.peek().map(String::as_str) {
+        Some("run") => Some(Command::Run),
+        Some("list") => Some(Command::List),
+        Some("check") => Some(Command::Check),
+        Some("export") => Some(Command::Export),
+        _ => None,
+    };
+    if let Some(command) = command {
+        options.command = command;
+        args.next();
+    }
+
+    let mut help = false;
+    let mut version = false;
+    let mut files = Vec::new();
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    let mut chains = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            files.extend(args.by_ref());
+            break;
+        } else if !arg.starts_with('-') || arg == "-" {
+            files.push(arg);
+            continue;
+        }
+
+        let (option, inline) = match arg.split_once('=') {
+            Some((option, value)) => (option.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+
+        let value = if VALUES.contains(&option.as_str()) {
+            match inline.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(CliError::Missing { option }),
+            }
+        } else if FLAGS.contains(&option.as_str()) {
+            if inline.is_some() {
+                return Err(CliError::Unexpected { option });
+            }
+            String::new()
+        } else {
+            let suggestion = FLAGS
+                .iter()
+                .chain(VALUES)
+                .map(|known| (crate::directive::distance(known, &option), *known))
+                .filter(|(distance, _)| *distance <= 2)
+                .min()
+                .map(|(_, known)| known);
+            return Err(CliError::Unknown { option, suggestion });
+        };
+
+        match option.as_str() {
+            "-h" | "--help" => help = true,
+            "-V" | "--version" => version = true,
+            // Kept for compatibility with older versions
+            "--list" => options.command = Command::List,
+            "--debug" => options.debug = true,
+            "--pty" => options.mode = Mode::Pty,
+            "--keep-going" => options.fail_fast = FailFast::Never,
+            "--sandbox" => options.sandbox = true,
+            // Both imply a sandbox
+            "--seed" => {
+                options.sandbox = true;
+                options.seed.push(value);
+            }
+            "--keep-sandbox" => {
+                options.sandbox = true;
+                options.keep_sandbox = true;
+            }
+            "--timeout" | "--file-timeout" => {
+                let Some(duration) = parse_duration(&value) else {
+                    return Err(CliError::Duration { option, value });
+                };
+                match option.as_str() {
+                    "--timeout" => options.timeout = Some(duration),
+                    _ => options.file_timeout = Some(duration),
+                }
+            }
+            "--fail-fast" => options.fail_fast = choice(&option, value, FAIL_FAST)?,
+            "--format" => options.format = choice(&option, value, FORMAT)?,
+            "--include" => include.push(value),
+            "--exclude" => exclude.push(value),
+            "-j" | "--jobs" => {
+                let Some(jobs) = value.parse().ok().filter(|jobs| *jobs > 0) else {
+                    return Err(CliError::Value {
+                        option,
+                        value,
+                        expected: "a positive number".to_string(),
+                    });
+                };
+                options.jobs = Some(jobs);
+            }
+            "--chain" => chains.push(value.split(',').map(String::from).collect()),
+            "--report" => {
+                let Some(path) = value.strip_prefix("junit=").filter(|path| !path.is_empty()) else {
+                    return Err(CliError::Value {
+                        option,
+                        value,
+                        expected: "junit=PATH".to_string(),
+                    });
+                };
+                options.junit = Some(path.to_string());
+            }
+            "--progress" => options.progress = choice(&option, value, PROGRESS)?,
+            "--color" => options.color = choice(&option, value, COLOR)?,
+            "--shell" | "--script" => {
+                let Some((language, command)) = value.split_once('=').filter(|(language, _)| !language.is_empty())
+                else {
+                    return Err(CliError::Value {
+                        option,
+                        value,
+                        expected: "LANG=COMMAND".to_string(),
+                    });
+                };
+                let runner = Runner {
+                    language: language.to_string(),
+                    command: command.to_string(),
+                    kind: match option.as_str() {
+                        "--shell" => Kind::Session,
+                        _ => Kind::Script,
+                    },
+                };
+                runner::register(&mut options.runners, runner);
+            }
+            // Loaded before parsing the rest of the command line
+            "--config" => {}
+            _ => unreachable!("Checked above"),
+        }
+    }
+
+    for (given, option) in [
+        (files, &mut options.files),
+        (include, &mut options.include),
+        (exclude, &mut options.exclude),
+    ] {
+        if !given.is_empty() {
+            *option = given;
+        }
+    }
+    if !chains.is_empty() {
+        options.chains = chains;
+    }
+
+    if help {
+        options.command = Command::Help;
+    } else if version {
+        options.command = Command::Version;
+    } else if options.files.is_empty() {
+        return Err(CliError::NoFiles);
+    }
+
+    Ok(options)
+}
+
+/// Looks up the value of an option among its choices.
+pub fn choice<T: Copy>(option: &str, value: String, choices: &[(&str, T)]) -> Result<T, CliError> {
+    match choices.iter().find(|(name, _)| *name == value) {
+        Some((_, choice)) => Ok(*choice),
+        None => Err(CliError::Value {
+            option: option.to_string(),
+            value,
+            expected: expected(choices),
+        }),
+    }
+}
+
+/// Lists the choices of an option, such as `all, file or never`.
+pub fn expected<T>(choices: &[(&str, T)]) -> String {
+    let names = choices.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    match names.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+        None => String::new(),
+    }
+}
+
+/// Parses durations such as `30s`, `500ms`, `2m` or `1h`. Durations without a unit are in seconds.
+pub fn parse_duration(duration: &str) -> Option<std::time::Duration> {
+    let n = duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len());
+    let value = duration[..n].parse::<u64>().ok()?;
+    match &duration[n..] {
+        "ms" => Some(std::time::Duration::from_millis(value)),
+        "" | "s" => Some(std::time::Duration::from_secs(value)),
+        "m" => value.checked_mul(60).map(std::time::Duration::from_secs),
+        "h" => value.checked_mul(3600).map(std::time::Duration::from_secs),
+        _ => None,
+    }
+}
@@ -1,12 +1,35 @@
-pub const RED: &str = "\x1b[0;31m";
-pub const GREEN: &str = "\x1b[0;32m";
-pub const YELLOW: &str = "\x1b[0;33m";
-pub const PURPLE: &str = "\x1b[0;35m";
+/// ANSI escape sequence which is only written out when colors are enabled.
+#[derive(Clone, Copy)]
+pub struct Color(&'static str);
 
-pub const BOLD: &str = "\x1b[1m";
-pub const FAINT: &str = "\x1b[2m";
-pub const ITALIC: &str = "\x1b[3m";
-pub const RESET: &str = "\x1b[m";
+static ENABLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
 
+pub fn set_colors(enabled: bool) {
+    ENABLED.store(enabled, std::sync::atomic::Ordering::Relaxed);
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if ENABLED.load(std::sync::atomic::Ordering::Relaxed) {
+            f.write_str(self.0)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub const RED: Color = Color("\x1b[0;31m");
+pub const GREEN: Color = Color("\x1b[0;32m");
+pub const YELLOW: Color = Color("\x1b[0;33m");
+pub const BLUE: Color = Color("\x1b[0;34m");
+pub const PURPLE: Color = Color("\x1b[0;35m");
+
+pub const BOLD: Color = Color("\x1b[1m");
+pub const FAINT: Color = Color("\x1b[2m");
+pub const ITALIC: Color = Color("\x1b[3m");
+pub const RESET: Color = Color("\x1b[m");
+
+// These are only written when redrawing code blocks as they run, so that long lines do not throw
+// off the number of lines to erase
 pub const WRAP_DISABLE: &str = "\x1b[?7l";
 pub const WRAP_ENABLE: &str = "\x1b[?7h";
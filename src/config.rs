@@ -0,0 +1,625 @@
+//! Reads defaults from a `md-command-check.toml` configuration file, such as:
+//!
+//! ```toml
+//! files = ["README.md", "docs"]
+//! exclude = ["drafts/**"]
+//! jobs = 4
+//! chains = [["docs/install.md", "docs/usage.md"]]
+//! timeout = "30s"
+//! ignore-languages = ["text", "json"]
+//!
+//! [shells]
+//! console = "bash"
+//!
+//! [scripts]
+//! python = "python3 -u"
+//!
+//! [env]
+//! API_URL = "http://localhost:8080"
+//!
+//! [report]
+//! junit = "target/report.xml"
+//! ```
+//!
+//! Only the subset of TOML needed for this is supported: tables, strings, integers, booleans and
+//! arrays. Paths are relative to the directory of the configuration file, except for `include` and
+//! `exclude`, whose patterns match the end of paths wherever the files are.
+
+use crate::cli::{self, Options};
+use crate::runner::{self, Kind, Runner};
+
+pub const FILE_NAME: &str = "md-command-check.toml";
+
+/// An invalid configuration file, at the given line if any.
+pub struct ConfigError {
+    pub line_number: usize,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+const TABLES: &[&str] = &["env", "report", "shells", "scripts"];
+
+/// Keys which can be set outside of a table.
+const KEYS: &[&str] = &[
+    "files",
+    "include",
+    "exclude",
+    "jobs",
+    "chains",
+    "debug",
+    "pty",
+    "timeout",
+    "file-timeout",
+    "fail-fast",
+    "format",
+    "progress",
+    "color",
+    "ignore-languages",
+    "sandbox",
+    "seed",
+    "keep-sandbox",
+];
+
+/// Finds the configuration file, either given with `--config` or looked up from the current
+/// directory up to the root of the repository.
+pub fn find(args: &[String]) -> Option<std::path::PathBuf> {
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(std::path::PathBuf::from);
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(std::path::PathBuf::from(path));
+        }
+    }
+
+    let depth = std::env::current_dir().ok()?.ancestors().count();
+    let mut dir = std::path::PathBuf::new();
+    for _ in 0..depth {
+        let path = dir.join(FILE_NAME);
+        if path.is_file() {
+            return Some(path);
+        } else if dir.join(".git").exists() {
+            return None;
+        }
+        dir.push("..");
+    }
+
+    None
+}
+
+/// Reads a configuration file into the default options.
+pub fn load(path: &std::path::Path, options: &mut Options) -> Result<(), ConfigError> {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return Err(ConfigError {
+            line_number: 0,
+            message: "Failed to read file".to_string(),
+        });
+    };
+
+    let base = path.parent().unwrap_or(std::path::Path::new(""));
+    let relative = |path: String| base.join(path).to_string_lossy().to_string();
+
+    for (line_number, table, key, value) in parse(&content)? {
+        let error = |message: String| ConfigError { line_number, message };
+        let string = |value: Value| match value {
+            Value::String(value) => Ok(value),
+            _ => Err(error(format!("Expected a string for {key}"))),
+        };
+        let strings = |value: Value| match value {
+            Value::Array(values) => values.into_iter().map(string).collect::<Result<Vec<_>, _>>(),
+            _ => Err(error(format!("Expected an array of strings for {key}"))),
+        };
+        let boolean = |value: Value| match value {
+            Value::Boolean(value) => Ok(value),
+            _ => Err(error(format!("Expected true or false for {key}"))),
+        };
+        let duration = |value: Value| match value {
+            Value::Integer(secs) if secs >= 0 => Ok(std::time::Duration::from_secs(secs as u64)),
+            Value::String(value) => {
+                cli::parse_duration(&value).ok_or_else(|| error(format!("Invalid duration for {key}")))
+            }
+            _ => Err(error(format!("Expected a duration such as \"30s\" for {key}"))),
+        };
+
+        match (table.as_str(), key.as_str()) {
+            ("", "files") => options.files = strings(value)?.into_iter().map(relative).collect(),
+            ("", "include") => options.include = strings(value)?,
+            ("", "exclude") => options.exclude = strings(value)?,
+            ("", "jobs") => match value {
+                Value::Integer(jobs) if jobs > 0 => options.jobs = Some(jobs as usize),
+                _ => return Err(error(format!("Expected a positive number for {key}"))),
+            },
+            ("", "chains") => {
+                let Value::Array(chains) = value else {
+                    return Err(error(format!("Expected an array of arrays of strings for {key}")));
+                };
+                options.chains = chains
+                    .into_iter()
+                    .map(|chain| Ok(strings(chain)?.into_iter().map(relative).collect()))
+                    .collect::<Result<_, _>>()?;
+            }
+            ("", "debug") => options.debug = boolean(value)?,
+            ("", "pty") => {
+                options.mode = match boolean(value)? {
+                    true => crate::shell::Mode::Pty,
+                    false => crate::shell::Mode::Pipe,
+                }
+            }
+            ("", "timeout") => options.timeout = Some(duration(value)?),
+            ("", "file-timeout") => options.file_timeout = Some(duration(value)?),
+            ("", "fail-fast") => options.fail_fast = choice(line_number, &key, value, cli::FAIL_FAST)?,
+            ("", "format") => options.format = choice(line_number, &key, value, cli::FORMAT)?,
+            ("", "progress") => options.progress = choice(line_number, &key, value, cli::PROGRESS)?,
+            ("", "color") => options.color = choice(line_number, &key, value, cli::COLOR)?,
+            ("", "ignore-languages") => options.ignore_languages = strings(value)?,
+            ("", "sandbox") => options.sandbox = boolean(value)?,
+            ("", "seed") => {
+                options.sandbox = true;
+                options.seed = strings(value)?.into_iter().map(relative).collect();
+            }
+            ("", "keep-sandbox") => {
+                options.keep_sandbox = boolean(value)?;
+                options.sandbox |= options.keep_sandbox;
+            }
+            ("env", _) => options.env.push((key.clone(), string(value)?)),
+            ("shells" | "scripts", _) => {
+                let runner = Runner {
+                    language: key.clone(),
+                    command: string(value)?,
+                    kind: match table.as_str() {
+                        "shells" => Kind::Session,
+                        _ => Kind::Script,
+                    },
+                };
+                runner::register(&mut options.runners, runner);
+            }
+            ("report", "junit") => options.junit = Some(relative(string(value)?)),
+            ("", _) | ("report", _) => {
+                let known = if table.is_empty() { KEYS } else { &["junit"] };
+                let suggestion = known
+                    .iter()
+                    .map(|known| (crate::directive::distance(known, &key), *known))
+                    .filter(|(distance, _)| *distance <= 2)
+                    .min()
+                    .map(|(_, known)| format!(", did you mean {known}?"))
+                    .unwrap_or_default();
+                return Err(error(format!("Unknown key {key}{suggestion}")));
+            }
+            _ => unreachable!("Tables are checked when parsing"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks up a string among the choices of a key.
+fn choice<T: Copy>(line_number: usize, key: &str, value: Value, choices: &[(&str, T)]) -> Result<T, ConfigError> {
+    let found = match &value {
+        Value::String(value) => choices.iter().find(|(name, _)| name == value),
+        _ => None,
+    };
+    match found {
+        Some((_, choice)) => Ok(*choice),
+        None => Err(ConfigError {
+            line_number,
+            message: format!("Expected {} for {key}", cli::expected(choices)),
+        }),
+    }
+}
+
+/// Parses a TOML file into a list of keys, along with their line and table.
+fn parse(content: &str) -> Result<Vec<(usize, String, String, Value)>, ConfigError> {
+    let mut parser = Parser {
+        chars: content.chars().peekable(),
+        line_number: 1,
+    };
+    let mut entries = Vec::new();
+    let mut table = String::new();
+
+    loop {
+        parser.skip_blank(true);
+        let line_number = parser.line_number;
+        match parser.chars.peek() {
+            None => break,
+            Some('[') => {
+                parser.chars.next();
+                table = parser.key()?;
+                parser.expect(']')?;
+                if !TABLES.contains(&table.as_str()) {
+                    return Err(parser.error(format!("Unknown table [{table}]")));
+                }
+            }
+            Some(_) => {
+                let key = parser.key()?;
+                parser.expect('=')?;
+                let value = parser.value()?;
+                entries.push((line_number, table.clone(), key, value));
+            }
+        }
+
+        // Every key or table is on its own line
+        parser.skip_blank(false);
+        match parser.chars.next() {
+            None | Some('\n') => parser.line_number += 1,
+            Some(c) => return Err(parser.error(format!("Unexpected {c:?}"))),
+        }
+    }
+
+    Ok(entries)
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line_number: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: String) -> ConfigError {
+        ConfigError {
+            line_number: self.line_number,
+            message,
+        }
+    }
+
+    /// Skips whitespace and comments, as well as line breaks if asked to.
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' if newlines => self.line_number += 1,
+                '#' => {
+                    while self.chars.next_if(|c| *c != '\n').is_some() {}
+                    continue;
+                }
+                _ => break,
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ConfigError> {
+        self.skip_blank(false);
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some('\n') | None => Err(self.error(format!("Expected {expected:?} before the end of the line"))),
+            Some(c) => Err(self.error(format!("Expected {expected:?}, got {c:?}"))),
+        }
+    }
+
+    /// Parses a bare or quoted key, as well as dotted table names such as `a.b`.
+    fn key(&mut self) -> Result<String, ConfigError> {
+        self.skip_blank(false);
+        let key = match self.chars.peek() {
+            Some('"') | Some('\'') => self.string()?,
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphanumeric() || "_-.".contains(*c)) {
+                    key.push(c);
+                }
+                key
+            }
+        };
+
+        if key.is_empty() {
+            return Err(self.error("Expected a key".to_string()));
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value, ConfigError> {
+        self.skip_blank(false);
+        match self.chars.peek() {
+            Some('"') | Some('\'') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.chars.next();
+                let mut values = Vec::new();
+                loop {
+                    self.skip_blank(true);
+                    if self.chars.next_if_eq(&']').is_some() {
+                        break;
+                    }
+                    values.push(self.value()?);
+                    self.skip_blank(true);
+                    if self.chars.next_if_eq(&',').is_none() {
+                        self.skip_blank(true);
+                        self.expect(']')?;
+                        break;
+                    }
+                }
+                Ok(Value::Array(values))
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphanumeric() || "_-+".contains(*c)) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => match word.replace('_', "").parse() {
+                        Ok(n) => Ok(Value::Integer(n)),
+                        Err(_) if word.is_empty() => Err(self.error("Expected a value".to_string())),
+                        Err(_) => Err(self.error(format!("Invalid value {word}"))),
+                    },
+                }
+            }
+        }
+    }
+
+    /// Parses a basic `"..."` string with escapes, or a literal `'...'` string.
+    fn string(&mut self) -> Result<String, ConfigError> {
+        let quote = self.chars.next().unwrap_or_default();
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some(c) if c == quote => return Ok(string),
+                Some('\\') if quote == '"' => match self.chars.next() {
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('u') => {
+                        let hex = self.chars.by_ref().take(4).collect::<String>();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => string.push(c),
+                            None => return Err(self.error(format!("Invalid escape \\u{hex}"))),
+                        }
+                    }
+                    c => return Err(self.error(format!("Invalid escape \\{}", c.unwrap_or_default()))),
+                },
+                Some('\n') | None => return Err(self.error("Unclosed string".to_string())),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(content: &str) -> Vec<(usize, String, String, Value)> {
+        match parse(content) {
+            Ok(entries) => entries,
+            Err(error) => panic!("line {}: {}", error.line_number, error.message),
+        }
+    }
+
+    fn value(content: &str) -> Value {
+        let mut entries = entries(content);
+        assert_eq!(entries.len(), 1);
+        entries.remove(0).3
+    }
+
+    fn error(content: &str) -> (usize, String) {
+        match parse(content) {
+            Ok(_) => panic!("Expected an error for {content:?}"),
+            Err(error) => (error.line_number, error.message),
+        }
+    }
+
+    /// Loads a configuration file written to a directory of its own.
+    fn load_str(name: &str, content: &str) -> Result<Options, ConfigError> {
+        let dir = std::env::temp_dir().join(format!("md-command-check-config-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(FILE_NAME);
+        std::fs::write(&path, content).unwrap();
+
+        let mut options = Options::default();
+        let result = load(&path, &mut options);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.map(|_| options)
+    }
+
+    fn string(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    #[test]
+    fn basic_strings() {
+        assert_eq!(value(r#"a = "text""#), string("text"));
+        assert_eq!(value(r#"a = """#), string(""));
+        assert_eq!(value(r##"a = "# not a comment""##), string("# not a comment"));
+        assert_eq!(value(r#"a = "\n\r\t\"\\""#), string("\n\r\t\"\\"));
+        assert_eq!(value(r#"a = "\u00e9\u2713""#), string("é✓"));
+    }
+
+    #[test]
+    fn literal_strings() {
+        assert_eq!(value(r"a = 'C:\path\n'"), string(r"C:\path\n"));
+        assert_eq!(value(r#"a = 'say "hi"'"#), string(r#"say "hi""#));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(value("a = 42"), Value::Integer(42));
+        assert_eq!(value("a = -3"), Value::Integer(-3));
+        assert_eq!(value("a = +3"), Value::Integer(3));
+        assert_eq!(value("a = 1_000"), Value::Integer(1000));
+    }
+
+    #[test]
+    fn booleans() {
+        assert_eq!(value("a = true"), Value::Boolean(true));
+        assert_eq!(value("a = false"), Value::Boolean(false));
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(value("a = []"), Value::Array(vec![]));
+        assert_eq!(
+            value(r#"a = ["x", 'y', 1, true]"#),
+            Value::Array(vec![string("x"), string("y"), Value::Integer(1), Value::Boolean(true)])
+        );
+        assert_eq!(
+            value(r#"a = [["x"], []]"#),
+            Value::Array(vec![Value::Array(vec![string("x")]), Value::Array(vec![])])
+        );
+    }
+
+    #[test]
+    fn multiline_arrays() {
+        let content = "a = [\n  \"x\", # first\n\n  \"y\",\n]\nb = 1\n";
+        let entries = entries(content);
+        assert_eq!(entries[0].3, Value::Array(vec![string("x"), string("y")]));
+        assert_eq!((entries[1].0, entries[1].2.as_str()), (6, "b"));
+    }
+
+    #[test]
+    fn comments() {
+        let content = "# comment\n\na = 1 # after a value\n  # indented\n[env] # after a table\nb = 2\n";
+        let entries = entries(content);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], (3, String::new(), "a".to_string(), Value::Integer(1)));
+        assert_eq!(entries[1], (6, "env".to_string(), "b".to_string(), Value::Integer(2)));
+    }
+
+    #[test]
+    fn tables() {
+        let content = "a = 1\n[shells]\nconsole = \"bash\"\n[ scripts ]\npython = \"python3\"\n";
+        let tables = entries(content)
+            .into_iter()
+            .map(|(_, table, key, _)| (table, key))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tables,
+            [("", "a"), ("shells", "console"), ("scripts", "python")]
+                .map(|(table, key)| (table.to_string(), key.to_string()))
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let content = "[env]\nA_B-c = 1\n\"with space\" = 2\n'literal' = 3\n";
+        let keys = entries(content).into_iter().map(|entry| entry.2).collect::<Vec<_>>();
+        assert_eq!(keys, ["A_B-c", "with space", "literal"]);
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(entries("a = 1\r\nb = 2\r\n").len(), 2);
+        assert_eq!(entries("").len(), 0);
+    }
+
+    #[test]
+    fn invalid_strings() {
+        assert_eq!(error("a = \"text"), (1, "Unclosed string".to_string()));
+        assert_eq!(error("\na = 'text\nb = 1"), (2, "Unclosed string".to_string()));
+        assert_eq!(error(r#"a = "\q""#), (1, r"Invalid escape \q".to_string()));
+        assert_eq!(error(r#"a = "\u12""#), (1, r#"Invalid escape \u12""#.to_string()));
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(error("a = "), (1, "Expected a value".to_string()));
+        assert_eq!(error("a = yes"), (1, "Invalid value yes".to_string()));
+        assert_eq!(error("a = 1.5"), (1, "Unexpected '.'".to_string()));
+        assert_eq!(error("a = [1 2]"), (1, "Expected ']', got '2'".to_string()));
+        assert_eq!(
+            error("a = [1"),
+            (1, "Expected ']' before the end of the line".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert_eq!(error("a"), (1, "Expected '=' before the end of the line".to_string()));
+        assert_eq!(error("a : 1"), (1, "Expected '=', got ':'".to_string()));
+        assert_eq!(error("= 1"), (1, "Expected a key".to_string()));
+        assert_eq!(error("a = 1 b = 2"), (1, "Unexpected 'b'".to_string()));
+        assert_eq!(error("a = \"x\" y"), (1, "Unexpected 'y'".to_string()));
+        assert_eq!(
+            error("[env"),
+            (1, "Expected ']' before the end of the line".to_string())
+        );
+        assert_eq!(error("a = 1\n\n[other]"), (3, "Unknown table [other]".to_string()));
+    }
+
+    #[test]
+    fn options() {
+        let content = "\
+files = [\"README.md\"]
+jobs = 4
+timeout = \"2m\"
+file-timeout = 30
+fail-fast = \"never\"
+
+[env]
+API_URL = \"http://localhost\"
+
+[report]
+junit = \"report.xml\"
+";
+        let options = load_str("options", content).ok().unwrap();
+        assert!(options.files[0].ends_with("/README.md"));
+        assert_eq!(options.jobs, Some(4));
+        assert_eq!(options.timeout, Some(std::time::Duration::from_secs(120)));
+        assert_eq!(options.file_timeout, Some(std::time::Duration::from_secs(30)));
+        assert!(options.fail_fast == cli::FailFast::Never);
+        assert_eq!(options.env, [("API_URL".to_string(), "http://localhost".to_string())]);
+        assert!(options.junit.is_some_and(|path| path.ends_with("/report.xml")));
+    }
+
+    #[test]
+    fn paths() {
+        let content = "files = [\"docs\"]\nchains = [[\"a.md\"]]\ninclude = [\"*.md\"]\nexclude = [\"drafts/**\"]\n";
+        let options = load_str("paths", content).ok().unwrap();
+        let dir = format!("md-command-check-config-{}-paths/", std::process::id());
+        assert!(options.files[0].ends_with(&format!("{dir}docs")));
+        assert!(options.chains[0][0].ends_with(&format!("{dir}a.md")));
+        assert_eq!(options.include, ["*.md"]);
+        assert_eq!(options.exclude, ["drafts/**"]);
+    }
+
+    #[test]
+    fn invalid_options() {
+        let error = |name: &str, content: &str| match load_str(name, content) {
+            Ok(_) => panic!("Expected an error for {content:?}"),
+            Err(error) => (error.line_number, error.message),
+        };
+        assert_eq!(
+            error("key", "jbos = 4"),
+            (1, "Unknown key jbos, did you mean jobs?".to_string())
+        );
+        assert_eq!(
+            error("report-key", "[report]\nxml = 1"),
+            (2, "Unknown key xml".to_string())
+        );
+        assert_eq!(
+            error("jobs", "jobs = 0"),
+            (1, "Expected a positive number for jobs".to_string())
+        );
+        assert_eq!(
+            error("string", "files = \"README.md\""),
+            (1, "Expected an array of strings for files".to_string())
+        );
+        assert_eq!(
+            error("strings", "files = [1]"),
+            (1, "Expected a string for files".to_string())
+        );
+        assert_eq!(
+            error("boolean", "debug = \"yes\""),
+            (1, "Expected true or false for debug".to_string())
+        );
+        assert_eq!(
+            error("duration", "timeout = \"soon\""),
+            (1, "Invalid duration for timeout".to_string())
+        );
+        assert_eq!(
+            error("chains", "chains = [\"a.md\"]"),
+            (1, "Expected an array of strings for chains".to_string())
+        );
+        assert_eq!(
+            error("choice", "color = \"blue\""),
+            (1, format!("Expected {} for color", cli::expected(cli::COLOR)))
+        );
+    }
+}
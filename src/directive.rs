@@ -0,0 +1,655 @@
+/// Instruction for `md-command-check`, applying to the next code block.
+///
+/// Directives are either written inside of comments, such as `<!-- timeout 10 -->`, or as
+/// attributes in the info string of a code block, such as ```` ```bash {timeout=10} ````.
+pub struct Directive {
+    pub line_number: usize,
+    pub name: String,
+    pub args: Vec<Arg>,
+}
+
+/// Directives which apply to the next code block.
+const ATTACHED: &[&str] = &[
+    "extract",
+    "kill",
+    "file",
+    "ignore",
+    "pty",
+    "input",
+    "expect-prompt",
+    "background",
+    "expect",
+    "exit-code",
+    "expect-error",
+    "timeout",
+    "deps",
+    "no_run",
+    "should_panic",
+    "compile_fail",
+    "session",
+];
+
+/// Directives which take effect as soon as they are encountered.
+const IMMEDIATE: &[&str] = &["env", "alias", "teardown"];
+
+/// Doctest attributes which are directives when following the language, as in `rust,no_run`. Other
+/// attributes such as `edition2021` are left to rustdoc.
+const DOCTEST: &[&str] = &["ignore", "no_run", "should_panic", "compile_fail"];
+
+/// Directives which take the rest of the line as a command or pattern, which is kept as is apart from a
+/// pair of quotes around it. `extract` takes the name of its variable first.
+const REST_OF_LINE: &[&str] = &["teardown", "extract", "kill", "background", "expect-error", "input"];
+
+/// Argument to a directive, optionally passed as `key=value`.
+pub struct Arg {
+    pub key: Option<String>,
+    /// Value of the argument, split on unquoted `:`.
+    pub parts: Vec<String>,
+}
+
+/// Malformed directive syntax.
+pub enum SyntaxError {
+    /// A quoted string or attribute list is missing its closing delimiter.
+    Unclosed { line_number: usize, delimiter: String },
+    /// A quoted string is directly followed by other characters.
+    Trailing { line_number: usize, text: String },
+    /// An unquoted argument contains a quote.
+    Quote { line_number: usize, text: String },
+    /// A `key=` option is missing its value.
+    Value { line_number: usize, key: String },
+}
+
+enum Token {
+    Arg(usize, Arg),
+    /// End of a directive, either a newline or a `;`.
+    End,
+}
+
+impl Directive {
+    /// Value of the `key=value` option with the given key, if any.
+    pub fn option(&self, key: &str) -> Option<String> {
+        self.args
+            .iter()
+            .find(|arg| arg.key.as_deref() == Some(key))
+            .map(Arg::value)
+    }
+
+    /// Whether this directive applies to the next code block, rather than taking effect immediately.
+    pub fn is_attached(&self) -> bool {
+        ATTACHED.contains(&self.name.as_str())
+    }
+
+    pub fn is_known(&self) -> bool {
+        is_known(&self.name)
+    }
+
+    /// Closest known directive name, to help with typos.
+    pub fn suggest(&self) -> Option<&'static str> {
+        suggest(&self.name)
+    }
+}
+
+impl Arg {
+    pub fn value(&self) -> String {
+        self.parts.join(":")
+    }
+}
+
+impl std::fmt::Display for Arg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{key}={}", self.value()),
+            None => write!(f, "{}", self.value()),
+        }
+    }
+}
+
+/// Extracts the directives held in a comment. Comments can hold several directives, one per line or
+/// separated by `;` when it is followed by the name of another directive.
+///
+/// Comments which do not start with a directive are regular comments such as
+/// `<!-- end of the install section -->` and hold no directives. Only directives with two letters
+/// swapped, such as `igonre`, are taken as typos, since other close words are likely to be prose.
+pub fn from_comment(line_number: usize, text: &str) -> Result<Vec<Directive>, SyntaxError> {
+    let name = word(text);
+    if !is_known(name) && !is_transposed(name) {
+        return Ok(Vec::new());
+    }
+
+    let mut directives = Vec::new();
+    let mut args = Vec::new();
+
+    for token in lex(line_number, text, true)?.into_iter().chain([Token::End]) {
+        match token {
+            Token::Arg(line_number, arg) => args.push((line_number, arg)),
+            Token::End if args.is_empty() => {}
+            Token::End => {
+                let mut args = std::mem::take(&mut args).into_iter();
+                let (line_number, name) = args.next().expect("Checked above");
+                let args = args.map(|(_, arg)| arg).collect();
+                directives.push(match name.key {
+                    // Directives can also be written as `name=value`, similarly to attributes
+                    Some(key) => Directive {
+                        line_number,
+                        name: key,
+                        args: parts_to_args(name.parts).chain(args).collect(),
+                    },
+                    None => Directive {
+                        line_number,
+                        name: name.value(),
+                        args,
+                    },
+                });
+            }
+        }
+    }
+
+    Ok(directives)
+}
+
+/// Splits the info string of a code block into its language and the directives declared in its
+/// attributes, as in ```` ```bash {ignore timeout=10 extract=VERSION:"v(\S+)"} ````.
+///
+/// Attributes are separated by whitespace. Arguments are passed as `key=value`, where multiple
+/// arguments are separated by `:`. Doctest attributes following the language and separated by `,`,
+/// as in ```` ```rust,no_run ````, are directives as well. Attributes meant for other tools, such as
+/// `{showLineNumbers}`, `{.class}`, `{#id}`, `{title="..."}` or line highlights like `{1,4-6}`, are
+/// ignored unless they are a directive with two letters swapped, just like comments.
+pub fn from_info(line_number: usize, info: &str) -> Result<(String, Vec<Directive>), SyntaxError> {
+    let mut words = info
+        .split(|c: char| c.is_whitespace() || c == '{')
+        .next()
+        .unwrap_or_default()
+        .split(',');
+    let lang = words.next().unwrap_or_default().to_string();
+    let mut directives = words
+        .filter(|name| DOCTEST.contains(name))
+        .map(|name| Directive {
+            line_number,
+            name: name.to_string(),
+            args: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+    let Some(start) = info.find('{') else {
+        return Ok((lang, directives));
+    };
+    let Some(len) = info[start..].rfind('}') else {
+        let delimiter = "}".to_string();
+        return Err(SyntaxError::Unclosed { line_number, delimiter });
+    };
+
+    for token in lex(line_number, &info[start + 1..start + len], false)? {
+        let Token::Arg(_, attr) = token else {
+            continue;
+        };
+
+        let directive = match attr.key {
+            Some(key) => Directive {
+                line_number,
+                name: key,
+                args: parts_to_args(attr.parts).collect(),
+            },
+            None => Directive {
+                line_number,
+                name: attr.value(),
+                args: Vec::new(),
+            },
+        };
+        if is_known(&directive.name) || is_transposed(&directive.name) {
+            directives.push(directive);
+        }
+    }
+
+    Ok((lang, directives))
+}
+
+fn parts_to_args(parts: Vec<String>) -> impl Iterator<Item = Arg> {
+    parts.into_iter().filter(|part| !part.is_empty()).map(|part| Arg {
+        key: None,
+        parts: vec![part],
+    })
+}
+
+/// Splits directives into arguments.
+///
+/// Arguments are separated by whitespace and can be quoted with `"..."`, in which case `\"`, `\\`,
+/// `\n`, `\r` and `\t` are unescaped. Other escape sequences are kept as is so that regexes such as
+/// `"\d+"` do not need double escaping. Raw strings such as `r"..."` or `r#"..."#` are taken
+/// literally.
+///
+/// Directives end at a newline, or at a `;` followed by the name of another directive. Unless lexing
+/// attributes, directives which take the rest of the line get it as a single argument, so that
+/// commands such as `teardown cd ..; echo "a  b"` are kept whole.
+fn lex(mut line_number: usize, text: &str, rest_of_line: bool) -> Result<Vec<Token>, SyntaxError> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    // Whether the rest of the line is the last argument of the directive being read
+    let is_rest = |tokens: &[Token]| {
+        let start = tokens
+            .iter()
+            .rposition(|token| matches!(token, Token::End))
+            .map_or(0, |n| n + 1);
+        match tokens.get(start) {
+            Some(Token::Arg(_, arg)) if arg.key.is_none() && REST_OF_LINE.contains(&arg.value().as_str()) => {
+                tokens.len() - start == 1 + (arg.value() == "extract") as usize
+            }
+            _ => false,
+        }
+    };
+
+    // Whether the `;` at `i` ends the directive, which is named by its first argument so far or
+    // else by the argument being read
+    let ends = |tokens: &[Token], part: &str, i: usize| {
+        let start = tokens
+            .iter()
+            .rposition(|token| matches!(token, Token::End))
+            .map_or(0, |n| n + 1);
+        let name = match tokens.get(start) {
+            Some(Token::Arg(_, arg)) => arg.key.clone().unwrap_or_else(|| arg.value()),
+            _ => part.to_string(),
+        };
+        !REST_OF_LINE.contains(&name.as_str()) && is_known(word(&chars[i + 1..].iter().collect::<String>()))
+    };
+
+    while let Some(&c) = chars.get(i) {
+        if c == '\n' || c == ';' && ends(&tokens, "", i) {
+            tokens.push(Token::End);
+            line_number += (c == '\n') as usize;
+            i += 1;
+            continue;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if rest_of_line && is_rest(&tokens) {
+            let len = chars[i..].iter().position(|c| *c == '\n').unwrap_or(chars.len() - i);
+            let rest = chars[i..i + len].iter().collect::<String>();
+            let arg = Arg {
+                key: None,
+                parts: vec![unquote(rest.trim_end()).to_string()],
+            };
+            tokens.push(Token::Arg(line_number, arg));
+            i += len;
+            continue;
+        }
+
+        let line_start = line_number;
+        let mut arg = Arg {
+            key: None,
+            parts: vec![String::new()],
+        };
+
+        loop {
+            // The first part of an argument can be a key, in which case it stops at the `=`
+            let key = arg.key.is_none() && arg.parts.len() == 1;
+            let part = arg.parts.last_mut().expect("Arguments always have a part");
+            let start = i;
+
+            if let Some((hashes, len)) = raw_prefix(&chars[i..]) {
+                let closing = format!("\"{}", "#".repeat(hashes));
+                let closing = closing.chars().collect::<Vec<_>>();
+                let Some(end) = chars[i + len..].windows(closing.len()).position(|w| w == closing) else {
+                    let delimiter = closing.iter().collect();
+                    return Err(SyntaxError::Unclosed { line_number, delimiter });
+                };
+                part.extend(&chars[i + len..i + len + end]);
+                i += len + end + closing.len();
+            } else if chars.get(i) == Some(&'"') {
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => {
+                            let delimiter = "\"".to_string();
+                            return Err(SyntaxError::Unclosed { line_number, delimiter });
+                        }
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.get(i + 1) {
+                                Some('"') => part.push('"'),
+                                Some('\\') => part.push('\\'),
+                                Some('n') => part.push('\n'),
+                                Some('r') => part.push('\r'),
+                                Some('t') => part.push('\t'),
+                                Some(&c) => part.extend(['\\', c]),
+                                None => part.push('\\'),
+                            }
+                            i += 1;
+                        }
+                        Some(&c) => part.push(c),
+                    }
+                    i += 1;
+                }
+                i += 1;
+            } else {
+                while let Some(&c) = chars.get(i) {
+                    if c.is_whitespace()
+                        || c == ':'
+                        || c == '"'
+                        || c == '=' && key
+                        || c == ';' && ends(&tokens, part, i)
+                    {
+                        break;
+                    }
+                    part.push(c);
+                    i += 1;
+                }
+
+                if chars.get(i) == Some(&'"') {
+                    let text = chars[start..].iter().take_while(|c| !c.is_whitespace()).collect();
+                    return Err(SyntaxError::Quote { line_number, text });
+                }
+            }
+
+            line_number += chars[start..i].iter().filter(|c| **c == '\n').count();
+
+            match chars.get(i) {
+                Some(':') => {
+                    arg.parts.push(String::new());
+                    i += 1;
+                }
+                Some('=') if arg.key.is_none() && arg.parts.len() == 1 => {
+                    let key = std::mem::take(&mut arg.parts[0]);
+                    i += 1;
+                    if chars.get(i).is_none_or(|c| c.is_whitespace() || *c == ';') {
+                        return Err(SyntaxError::Value { line_number, key });
+                    }
+                    arg.key = Some(key);
+                }
+                Some(c) if !c.is_whitespace() && *c != ';' => {
+                    let text = chars[i..].iter().take_while(|c| !c.is_whitespace()).collect();
+                    return Err(SyntaxError::Trailing { line_number, text });
+                }
+                _ => break,
+            }
+        }
+
+        tokens.push(Token::Arg(line_start, arg));
+    }
+
+    Ok(tokens)
+}
+
+/// Removes a pair of matching quotes around text, if any.
+fn unquote(text: &str) -> &str {
+    ['"', '\'']
+        .into_iter()
+        .find_map(|quote| text.strip_prefix(quote)?.strip_suffix(quote))
+        .unwrap_or(text)
+}
+
+/// Unescapes the `\"`, `\\`, `\n`, `\r` and `\t` escape sequences, keeping any other as is like quoted
+/// arguments do.
+pub fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => unescaped.push('"'),
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => unescaped.extend(['\\', c]),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Matches the start of a raw string, such as `r"` or `r##"`, returning the number of `#` and the
+/// length of the prefix.
+fn raw_prefix(chars: &[char]) -> Option<(usize, usize)> {
+    if chars.first() != Some(&'r') {
+        return None;
+    }
+    let hashes = chars[1..].iter().take_while(|c| **c == '#').count();
+    (chars.get(hashes + 1) == Some(&'"')).then_some((hashes, hashes + 2))
+}
+
+/// Closest known directive name, to help with typos.
+fn suggest(name: &str) -> Option<&'static str> {
+    ATTACHED
+        .iter()
+        .chain(IMMEDIATE)
+        .map(|known| (distance(known, name), *known))
+        .filter(|(distance, _)| *distance <= 2 && *distance < name.len())
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// Whether a name is a known directive name with two adjacent characters swapped.
+fn is_transposed(name: &str) -> bool {
+    let name = name.chars().collect::<Vec<_>>();
+    ATTACHED.iter().chain(IMMEDIATE).any(|known| {
+        let known = known.chars().collect::<Vec<_>>();
+        known.len() == name.len()
+            && (1..known.len()).any(|i| {
+                known[i - 1] != known[i]
+                    && name[i - 1] == known[i]
+                    && name[i] == known[i - 1]
+                    && name[..i - 1] == known[..i - 1]
+                    && name[i + 1..] == known[i + 1..]
+            })
+    })
+}
+
+fn is_known(name: &str) -> bool {
+    ATTACHED.contains(&name) || IMMEDIATE.contains(&name)
+}
+
+/// First word of a directive, which is its name.
+fn word(text: &str) -> &str {
+    let text = text.trim_start();
+    let end = text
+        .find(|c: char| c.is_whitespace() || ";=:\"".contains(c))
+        .unwrap_or(text.len());
+    &text[..end]
+}
+
+/// Levenshtein distance between two strings.
+pub fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names and arguments of the directives in a comment.
+    fn comment(text: &str) -> Vec<(String, Vec<String>)> {
+        match from_comment(1, text) {
+            Ok(directives) => directives
+                .into_iter()
+                .map(|directive| (directive.name, directive.args.iter().map(ToString::to_string).collect()))
+                .collect(),
+            Err(_) => panic!("Syntax error in {text:?}"),
+        }
+    }
+
+    fn args(text: &str) -> Vec<String> {
+        let mut directives = comment(text);
+        assert_eq!(directives.len(), 1);
+        directives.remove(0).1
+    }
+
+    fn error(text: &str) -> SyntaxError {
+        match from_comment(1, text) {
+            Ok(_) => panic!("Expected a syntax error in {text:?}"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn quoted_arguments() {
+        assert_eq!(args("file \"my file.txt\""), ["my file.txt"]);
+        assert_eq!(
+            args(r#"expect-prompt "a \"b\" \\ \n\t \d" answer yes"#),
+            ["a \"b\" \\ \n\t \\d", "answer", "yes"]
+        );
+        assert_eq!(args(r#"file """#), [""]);
+    }
+
+    #[test]
+    fn raw_arguments() {
+        assert_eq!(args(r#"expect-prompt r"\n \d" answer y"#)[0], r"\n \d");
+        assert_eq!(args(r###"expect-prompt r#"say "yes""# answer y"###)[0], r#"say "yes""#);
+        assert_eq!(args(r####"expect-prompt r##"a "# b"## answer y"####)[0], r##"a "# b"##);
+    }
+
+    #[test]
+    fn options_and_parts() {
+        let directives = from_comment(1, "expect-prompt \"Name:\" answer=\"a b\" deps=x:1.0")
+            .ok()
+            .unwrap();
+        let args = &directives[0].args;
+        assert_eq!(args[0].key, None);
+        assert_eq!(args[0].parts, ["Name:"]);
+        assert_eq!(args[1].key.as_deref(), Some("answer"));
+        assert_eq!(args[1].parts, ["a b"]);
+        assert_eq!(args[2].key.as_deref(), Some("deps"));
+        assert_eq!(args[2].parts, ["x", "1.0"]);
+        assert_eq!(directives[0].option("answer").as_deref(), Some("a b"));
+    }
+
+    #[test]
+    fn name_value_form() {
+        assert_eq!(comment("timeout=10"), [("timeout".to_string(), vec!["10".to_string()])]);
+        assert_eq!(comment("deps=a=1"), [("deps".to_string(), vec!["a=1".to_string()])]);
+    }
+
+    #[test]
+    fn several_directives() {
+        let names = |text| comment(text).into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names("ignore\ntimeout 10"), ["ignore", "timeout"]);
+        assert_eq!(names("expect; exit-code 0"), ["expect", "exit-code"]);
+        assert_eq!(names("timeout 10;ignore"), ["timeout", "ignore"]);
+        assert_eq!(names("\n  ignore\n\n"), ["ignore"]);
+        assert_eq!(args("file \"a;ignore\""), ["a;ignore"]);
+    }
+
+    #[test]
+    fn rest_of_line() {
+        assert_eq!(args("teardown cd ..; echo done"), ["cd ..; echo done"]);
+        assert_eq!(args("teardown cd ..; ignore"), ["cd ..; ignore"]);
+        assert_eq!(
+            args(r#"teardown sh -c 'echo "a  b" > out.txt'"#),
+            [r#"sh -c 'echo "a  b" > out.txt'"#]
+        );
+        assert_eq!(args(r#"teardown printf '%s\n' "a  b""#), [r#"printf '%s\n' "a  b""#]);
+        assert_eq!(args(r#"input 'say "hi"'"#), [r#"say "hi""#]);
+        assert_eq!(args(r#"input "y\n"  "#), [r"y\n"]);
+        assert_eq!(args(r#"extract VERSION "v(\S+)" "#), ["VERSION", r"v(\S+)"]);
+        assert_eq!(args("kill exit now"), ["exit now"]);
+        assert_eq!(args("background"), Vec::<String>::new());
+
+        let directives = comment("background ready\ntimeout 10");
+        assert_eq!(directives[0].1, ["ready"]);
+        assert_eq!(directives[1].0, "timeout");
+    }
+
+    #[test]
+    fn info_strings() {
+        let names = |info| {
+            let (lang, directives) = from_info(1, info).ok().unwrap();
+            (
+                lang,
+                directives
+                    .into_iter()
+                    .map(|directive| directive.name)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(names("bash"), ("bash".to_string(), vec![]));
+        assert_eq!(
+            names("rust,no_run,edition2021"),
+            ("rust".to_string(), vec!["no_run".to_string()])
+        );
+        assert_eq!(
+            names("bash {1,4-6 ignore}"),
+            ("bash".to_string(), vec!["ignore".to_string()])
+        );
+        assert_eq!(
+            names(r#"bash {showLineNumbers .class #id title="a b" timeout=10}"#),
+            ("bash".to_string(), vec!["timeout".to_string()])
+        );
+        assert_eq!(names("bash {igonre}"), ("bash".to_string(), vec!["igonre".to_string()]));
+
+        // Attributes never take the rest of the line
+        let (_, directives) = from_info(1, "bash {background timeout=10}").ok().unwrap();
+        assert_eq!(directives.len(), 2);
+        assert!(directives[0].args.is_empty());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(matches!(error("file \"a"), SyntaxError::Unclosed { .. }));
+        assert!(matches!(
+            error("expect-prompt r#\"a\" answer"),
+            SyntaxError::Unclosed { .. }
+        ));
+        assert!(matches!(error("file \"a\"b"), SyntaxError::Trailing { .. }));
+        assert!(matches!(error("file a\"b\""), SyntaxError::Quote { .. }));
+        assert!(matches!(error("timeout="), SyntaxError::Value { .. }));
+        assert!(matches!(
+            from_info(1, "bash {ignore"),
+            Err(SyntaxError::Unclosed { .. })
+        ));
+    }
+
+    #[test]
+    fn regular_comments() {
+        assert!(comment("TODO: rewrite this section").is_empty());
+        assert!(comment("end of the install section").is_empty());
+        assert!(comment("Don't \"quote\" me").is_empty());
+        assert_eq!(comment("igonre")[0].0, "igonre");
+    }
+
+    #[test]
+    fn transpositions() {
+        assert!(is_transposed("igonre"));
+        assert!(is_transposed("tiemout"));
+        assert!(!is_transposed("ignore"));
+        assert!(!is_transposed("end"));
+        assert!(!is_transposed("export"));
+        assert!(!is_transposed(""));
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(suggest("igonre"), Some("ignore"));
+        assert_eq!(suggest("timout"), Some("timeout"));
+        assert_eq!(suggest("exit_code"), Some("exit-code"));
+        assert_eq!(suggest("completely-different"), None);
+        assert_eq!(suggest("a"), None);
+    }
+
+    #[test]
+    fn distances() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("abc", ""), 3);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("ignore", "igonre"), 2);
+        assert_eq!(distance("env", "end"), 1);
+        assert_eq!(distance("é", "e"), 1);
+    }
+}
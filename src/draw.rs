@@ -1,12 +1,17 @@
 use std::io::Write;
 
 use crate::colors::*;
+use crate::expect::{Code, Diff};
+use crate::summary::Summary;
 
 #[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Status {
     RUNNING,
     PASS,
     FAIL,
+    TIMEOUT,
+    BACKGROUND,
     NEWFILE,
 }
 
@@ -19,14 +24,20 @@ pub fn draw_file_info(
     status: Status,
     file_name: &str,
     line_number: usize,
+    session: Option<&str>,
 ) -> std::io::Result<usize> {
+    let block = match session {
+        Some(session) => format!("code block at line {line_number} in session {ITALIC}{session}{RESET}"),
+        None => format!("code block at line {line_number}"),
+    };
+
     match status {
         Status::RUNNING => {
             writeln!(
                 out,
                 "\
                     {YELLOW}╭[    {RESET}{BOLD}{file_name}{RESET}: \
-                    code block at line {line_number} - \
+                    {block} - \
                     {YELLOW}{ITALIC}RUNNING{RESET}\
                 "
             )
@@ -36,7 +47,7 @@ pub fn draw_file_info(
                 out,
                 "\
                     {GREEN}╭[ ✅ {RESET}{BOLD}{file_name}{RESET}: \
-                    code block at line {line_number} - \
+                    {block} - \
                     {GREEN}PASS{RESET}\
                 "
             )
@@ -46,17 +57,37 @@ pub fn draw_file_info(
                 out,
                 "\
                     {RED}╭[ ❌ {RESET}{BOLD}{file_name}{RESET}: \
-                    code block at line {line_number} - \
+                    {block} - \
                     {RED}FAIL{RESET}\
                 "
             )
         }
+        Status::TIMEOUT => {
+            writeln!(
+                out,
+                "\
+                    {RED}╭[ ⏰ {RESET}{BOLD}{file_name}{RESET}: \
+                    {block} - \
+                    {RED}TIMEOUT{RESET}\
+                "
+            )
+        }
+        Status::BACKGROUND => {
+            writeln!(
+                out,
+                "\
+                    {BLUE}╭[ 🔄 {RESET}{BOLD}{file_name}{RESET}: \
+                    {block} - \
+                    {BLUE}BACKGROUND{RESET}\
+                "
+            )
+        }
         Status::NEWFILE => {
             writeln!(
                 out,
                 "\
                     {PURPLE}╭[ 📁 {RESET}{BOLD}{file_name}{RESET}: \
-                    code block at line {line_number} - \
+                    {block} - \
                     {PURPLE}NEW FILE{RESET}\
                 "
             )
@@ -69,6 +100,7 @@ pub fn draw_file_info(
 pub fn draw_code(
     out: &mut impl std::io::Write,
     status: Status,
+    fence: &str,
     lang: &str,
     program_and_args: &str,
     terminate: bool,
@@ -82,18 +114,18 @@ pub fn draw_code(
         write!(
             out,
             "\
-                {accent}│{RESET} ```{ITALIC}{lang}{RESET}\n\
+                {accent}│{RESET} {fence}{ITALIC}{lang}{RESET}\n\
                 {accent}│{RESET} {program_and_args}\n\
-                {accent}│{RESET} ```\n\
+                {accent}│{RESET} {fence}\n\
             ",
         )?;
     } else {
         write!(
             out,
             "\
-                {accent}│{RESET} ```{ITALIC}{lang}{RESET}\n\
+                {accent}│{RESET} {fence}{ITALIC}{lang}{RESET}\n\
                 {accent}│{RESET} {program_and_args}\n\
-                {accent}╰{RESET} ```\n\
+                {accent}╰{RESET} {fence}\n\
             ",
         )?;
     }
@@ -158,11 +190,126 @@ pub fn draw_output(
     }
 }
 
-pub fn accent(status: Status) -> &'static str {
+pub fn draw_exit_code(
+    out: &mut impl std::io::Write,
+    status: Status,
+    code: i32,
+    expected: Code,
+    terminate: bool,
+) -> std::io::Result<usize> {
+    let accent = accent(status);
+    if !terminate {
+        writeln!(
+            out,
+            "{accent}│{RESET} >> {BOLD}{ITALIC}exit code{RESET} {code} {FAINT}(expected {expected}){RESET}"
+        )?;
+    } else {
+        writeln!(
+            out,
+            "{accent}╰{RESET} >> {BOLD}{ITALIC}exit code{RESET} {code} {FAINT}(expected {expected}){RESET}"
+        )?;
+    }
+    Ok(1)
+}
+
+pub fn draw_diff(out: &mut impl std::io::Write, status: Status, diff: &[Diff], output: &str) -> std::io::Result<usize> {
+    let accent = accent(status);
+    write!(
+        out,
+        "\
+            {accent}│{RESET} >> {BOLD}{ITALIC}{output}{RESET} {FAINT}({RED}-expected{RESET}{FAINT}, \
+            {GREEN}+actual{RESET}{FAINT}){RESET}\n\
+            {accent}│{RESET} >>\n\
+        "
+    )?;
+
+    for line in diff {
+        match line {
+            Diff::Same(line) => writeln!(out, "{accent}│{RESET} >>  {line}")?,
+            Diff::Expected(line) => writeln!(out, "{accent}│{RESET} >> {RED}-{line}{RESET}")?,
+            Diff::Actual(line) => writeln!(out, "{accent}│{RESET} >> {GREEN}+{line}{RESET}")?,
+        }
+    }
+
+    writeln!(out, "{accent}╰{RESET} >>")?;
+    Ok(diff.len() + 3)
+}
+
+pub fn draw_summary(out: &mut impl std::io::Write, summary: &Summary) -> std::io::Result<usize> {
+    let (accent, icon) = if summary.failures.is_empty() {
+        (GREEN, "✅")
+    } else {
+        (RED, "❌")
+    };
+
+    writeln!(
+        out,
+        "\
+            {accent}╭[ {icon} {RESET}{BOLD}Summary{RESET}: \
+            {} passed, {} failed, {} ignored, {} files created \
+            {FAINT}({}){RESET}\
+        ",
+        summary.passed,
+        summary.failures.len(),
+        summary.ignored,
+        summary.files_created,
+        duration(summary.start.elapsed())
+    )?;
+
+    let mut lines = Vec::with_capacity(summary.files.len() + summary.failures.len() + 3);
+    match &summary.sandbox {
+        Some((path, true)) => lines.push(format!("sandbox kept at {ITALIC}{path}{RESET}")),
+        Some((path, false)) => lines.push(format!("sandbox at {ITALIC}{path}{RESET} {FAINT}(removed){RESET}")),
+        None => {}
+    }
+    for (file_name, elapsed) in summary.files.iter() {
+        lines.push(format!("{file_name} {FAINT}({}){RESET}", duration(*elapsed)));
+    }
+
+    if !summary.failures.is_empty() {
+        lines.push(format!(">> {BOLD}{ITALIC}failures{RESET}"));
+        for failure in summary.failures.iter() {
+            let file_name = &failure.file_name;
+            let line_number = failure.line_number;
+            match failure.duration {
+                Some(elapsed) => lines.push(format!(
+                    ">> {RED}{file_name}:{line_number}{RESET} {FAINT}({}){RESET}",
+                    duration(elapsed)
+                )),
+                // Files which could not be read at all have no line to point to
+                None if line_number == 0 => lines.push(format!(">> {RED}{file_name}{RESET}")),
+                None => lines.push(format!(">> {RED}{file_name}:{line_number}{RESET}")),
+            }
+        }
+    }
+
+    for (i, line) in lines.iter().enumerate() {
+        if i + 1 < lines.len() {
+            writeln!(out, "{accent}│{RESET} {line}")?;
+        } else {
+            writeln!(out, "{accent}╰{RESET} {line}")?;
+        }
+    }
+
+    Ok(lines.len() + 1)
+}
+
+/// Formats a duration as milliseconds or seconds, depending on how long it is.
+pub fn duration(duration: std::time::Duration) -> String {
+    if duration.as_secs() == 0 {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.2}s", duration.as_secs_f64())
+    }
+}
+
+pub fn accent(status: Status) -> Color {
     match status {
         Status::RUNNING => YELLOW,
         Status::PASS => GREEN,
         Status::FAIL => RED,
+        Status::TIMEOUT => RED,
+        Status::BACKGROUND => BLUE,
         Status::NEWFILE => PURPLE,
     }
 }
@@ -178,9 +325,90 @@ pub fn count_lines(mut data: &str) -> usize {
     count
 }
 
+/// Whether stdout is reserved for machine-readable output, such as TAP. Code blocks are then not
+/// drawn, and messages are written to stderr instead.
+static MACHINE_OUTPUT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub fn set_machine_output() {
+    MACHINE_OUTPUT.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn is_machine_output() -> bool {
+    MACHINE_OUTPUT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Where messages meant for humans are written.
+pub fn term() -> Box<dyn std::io::Write> {
+    if is_machine_output() {
+        stream(false)
+    } else {
+        stream(true)
+    }
+}
+
+/// Where machine-readable output, such as events, is written.
+pub fn machine() -> Box<dyn std::io::Write> {
+    stream(true)
+}
+
 pub fn flush(out: &mut Vec<u8>) -> std::io::Result<()> {
-    let mut stdout = std::io::stdout();
+    if is_machine_output() {
+        out.clear();
+        return Ok(());
+    }
+
+    let mut stdout = stream(true);
     stdout.write_all(out)?;
     out.clear();
     stdout.flush()
 }
+
+/// Output held back by a thread, so that files run in parallel are written one after the other
+/// rather than interleaved.
+#[derive(Default)]
+pub struct Buffer {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+thread_local! {
+    static BUFFER: std::cell::RefCell<Option<Buffer>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Holds back everything written by the current thread until it is taken with [take_buffer].
+pub fn set_buffered() {
+    BUFFER.with_borrow_mut(|buffer| *buffer = Some(Buffer::default()));
+}
+
+/// Takes the output held back by the current thread so far.
+pub fn take_buffer() -> Buffer {
+    BUFFER.with_borrow_mut(|buffer| buffer.as_mut().map(std::mem::take).unwrap_or_default())
+}
+
+/// Writer to stdout or stderr, or to the buffer of the current thread if any.
+fn stream(stdout: bool) -> Box<dyn std::io::Write> {
+    match (BUFFER.with_borrow(Option::is_some), stdout) {
+        (true, _) => Box::new(Buffered { stdout }),
+        (false, true) => Box::new(std::io::stdout()),
+        (false, false) => Box::new(std::io::stderr()),
+    }
+}
+
+struct Buffered {
+    stdout: bool,
+}
+
+impl std::io::Write for Buffered {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        BUFFER.with_borrow_mut(|buffer| match (buffer, self.stdout) {
+            (Some(buffer), true) => buffer.stdout.extend_from_slice(buf),
+            (Some(buffer), false) => buffer.stderr.extend_from_slice(buf),
+            (None, _) => {}
+        });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
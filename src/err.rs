@@ -1,14 +1,19 @@
 use std::io::Write as _;
 
+use crate::cli::CliError;
 use crate::colors::*;
+use crate::config::ConfigError;
+use crate::directive::SyntaxError;
+use crate::draw::term;
+use crate::files::Unresolved;
 
 pub fn listed(file_name: &str, line_number: usize, program_and_args: &str, debug: bool) -> std::io::Result<()> {
-    write!(
-        std::io::stdout(),
+    writeln!(
+        term(),
         "\
             {YELLOW}╭[    {RESET}{BOLD}{file_name}{RESET}: \
             code block at line {line_number} - \
-            {YELLOW}ACTIVE{RESET}\n\
+            {YELLOW}ACTIVE{RESET}\
         "
     )?;
 
@@ -20,12 +25,12 @@ pub fn listed(file_name: &str, line_number: usize, program_and_args: &str, debug
 }
 
 pub fn ignored(file_name: &str, line_number: usize, program_and_args: &str, debug: bool) -> std::io::Result<()> {
-    write!(
-        std::io::stdout(),
+    writeln!(
+        term(),
         "\
             ╭[    {BOLD}{file_name}{RESET}: \
             code block at line {line_number} - \
-            {FAINT}{ITALIC}IGNORED{RESET}\n\
+            {FAINT}{ITALIC}IGNORED{RESET}\
         "
     )?;
 
@@ -37,12 +42,12 @@ pub fn ignored(file_name: &str, line_number: usize, program_and_args: &str, debu
 }
 
 pub fn teardown(file_name: &str, line_number: usize, cmd: &str) -> std::io::Result<()> {
-    write!(
-        std::io::stdout(),
+    writeln!(
+        term(),
         "\
             {PURPLE}╭[ 🤖 {BOLD}{file_name}{RESET}: \
             custom command at line {line_number} - \
-            {PURPLE}TEARDOWN{RESET}\n\
+            {PURPLE}TEARDOWN{RESET}\
         "
     )?;
 
@@ -51,121 +56,381 @@ pub fn teardown(file_name: &str, line_number: usize, cmd: &str) -> std::io::Resu
     Ok(())
 }
 
+pub fn stopped(file_name: &str, line_number: usize, output: &str, debug: bool) -> std::io::Result<()> {
+    writeln!(
+        term(),
+        "\
+            {BLUE}╭[ 🛑 {BOLD}{file_name}{RESET}: \
+            background code block at line {line_number} - \
+            {BLUE}STOPPED{RESET}\
+        "
+    )?;
+
+    if debug {
+        log_stdout(output.to_string(), true, BLUE)?;
+    }
+
+    Ok(())
+}
+
+pub fn checked(file_name: &str) -> std::io::Result<()> {
+    writeln!(
+        term(),
+        "\
+            {GREEN}╭[ ✅ {RESET}{BOLD}{file_name}{RESET}: \
+            {GREEN}VALID{RESET}\
+        "
+    )?;
+
+    Ok(())
+}
+
 pub fn err(file_name: &str) -> std::io::Result<std::process::ExitCode> {
-    write!(std::io::stdout(), "{RED}╭[ ❌ {RESET}{BOLD}{file_name}{RESET}: ")?;
+    write!(term(), "{RED}╭[ ❌ {RESET}{BOLD}{file_name}{RESET}: ")?;
     Ok(std::process::ExitCode::FAILURE)
 }
 
-pub fn err_line_directive(
-    file_name: &str,
-    line_number: usize,
-    directive: &str,
-) -> std::io::Result<std::process::ExitCode> {
-    err(file_name)?;
-    write!(std::io::stdout(), "{directive} directive at line {line_number} - ")?;
-    Ok(std::process::ExitCode::FAILURE)
+/// Error which aborted a file, once written to the terminal.
+pub struct Abort {
+    /// Error without colors, such as `timeout directive at line 3 - Invalid duration: 10x`.
+    pub message: String,
 }
 
-pub fn err_line_code(file_name: &str, line_number: usize) -> std::io::Result<std::process::ExitCode> {
+/// Writes an error made of the location it happened at, if any, and of text in the given colors.
+/// Returns the error without colors.
+fn error(file_name: &str, location: Option<String>, text: &[(Color, &str)]) -> std::io::Result<String> {
     err(file_name)?;
-    write!(std::io::stdout(), "code block at line {line_number} - ")?;
-    Ok(std::process::ExitCode::FAILURE)
+    let mut out = term();
+    let mut message = String::new();
+    if let Some(location) = location {
+        write!(out, "{location} - ")?;
+        message = format!("{location} - ");
+    }
+    for (color, text) in text {
+        write!(out, "{color}{text}{RESET}")?;
+        message.push_str(text);
+    }
+    writeln!(out)?;
+    Ok(message)
 }
 
-pub fn err_file_ext(file_name: &str) -> std::io::Result<std::process::ExitCode> {
-    err(file_name)?;
-    write!(std::io::stdout(), "{RED}File is not a .md{RESET}\n")?;
-    Ok(std::process::ExitCode::FAILURE)
+fn abort(file_name: &str, location: Option<String>, text: &[(Color, &str)]) -> std::io::Result<Abort> {
+    let message = error(file_name, location, text)?;
+    Ok(Abort { message })
 }
 
-pub fn err_file_open(file_name: &str) -> std::io::Result<std::process::ExitCode> {
-    err(file_name)?;
-    write!(std::io::stdout(), "{RED}Failed to open file{RESET}\n")?;
-    Ok(std::process::ExitCode::FAILURE)
+fn at_directive(directive: &str, line_number: usize) -> Option<String> {
+    Some(format!("{directive} directive at line {line_number}"))
 }
 
-pub fn err_extract_no_var(file_name: &str, line_number: usize) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "extract")?;
-    write!(std::io::stdout(), "{RED}No variable name{RESET}\n")?;
-    Ok(std::process::ExitCode::FAILURE)
+fn at_code(line_number: usize) -> Option<String> {
+    Some(format!("code block at line {line_number}"))
 }
 
-pub fn err_env_no_var(file_name: &str, line_number: usize) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "env")?;
-    write!(std::io::stdout(), "{RED}No variable name{RESET}\n")?;
-    Ok(std::process::ExitCode::FAILURE)
+pub fn err_file_ext(file_name: &str) -> std::io::Result<Abort> {
+    abort(file_name, None, &[(RED, "File is not a .md, .markdown or .mdx")])
 }
 
-pub fn err_env_not_set(file_name: &str, line_number: usize, var: &str) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "env")?;
-    write!(
-        std::io::stdout(),
-        "{RED}Variable was not set:{RESET} {ITALIC}{var}{RESET}\n"
-    )?;
-    Ok(std::process::ExitCode::FAILURE)
+pub fn err_file_open(file_name: &str) -> std::io::Result<Abort> {
+    abort(file_name, None, &[(RED, "Failed to open file")])
 }
 
-pub fn err_alias_no_var(file_name: &str, line_number: usize) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "alias")?;
-    write!(std::io::stdout(), "{RED}No variable name{RESET}\n")?;
+pub fn err_unresolved(unresolved: &Unresolved) -> std::io::Result<std::process::ExitCode> {
+    match unresolved {
+        Unresolved::Empty { path } => {
+            err(path)?;
+            writeln!(term(), "{RED}No Markdown files found{RESET}")?;
+        }
+        Unresolved::Read { path } => {
+            err(path)?;
+            writeln!(term(), "{RED}Failed to read directory{RESET}")?;
+        }
+    }
     Ok(std::process::ExitCode::FAILURE)
 }
 
-pub fn err_alias_not_captured(
-    file_name: &str,
-    line_number: usize,
-    var: &str,
-) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "alias")?;
-    write!(
-        std::io::stdout(),
-        "{RED}Variable has not been previously captured:{RESET} {ITALIC}{var}{RESET}\n"
-    )?;
+pub fn err_cli(error: &CliError) -> std::io::Result<std::process::ExitCode> {
+    match error {
+        CliError::Unknown { option, suggestion } => {
+            err(option)?;
+            write!(term(), "{RED}Unknown option{RESET}")?;
+            match suggestion {
+                Some(suggestion) => writeln!(term(), "{RED}, did you mean{RESET} {suggestion}{RED}?{RESET}")?,
+                None => writeln!(term())?,
+            }
+        }
+        CliError::Missing { option } => {
+            err(option)?;
+            writeln!(term(), "{RED}Missing value{RESET}")?;
+        }
+        CliError::Unexpected { option } => {
+            err(option)?;
+            writeln!(term(), "{RED}Option does not take a value{RESET}")?;
+        }
+        CliError::Value {
+            option,
+            value,
+            expected,
+        } => {
+            err(option)?;
+            writeln!(term(), "{RED}Expected {expected}, got{RESET} {ITALIC}{value}{RESET}")?;
+        }
+        CliError::Duration { option, value } => {
+            err(option)?;
+            writeln!(term(), "{RED}Invalid duration:{RESET} {ITALIC}{value}{RESET}")?;
+        }
+        CliError::NoFiles => {
+            err("md-command-check")?;
+            writeln!(term(), "{RED}No files given, see{RESET} --help")?;
+        }
+    }
     Ok(std::process::ExitCode::FAILURE)
 }
 
-pub fn err_extract_pattern(file_name: &str, line_number: usize, pat: &str) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "extract")?;
-    write!(std::io::stdout(), "{RED}Invalid pattern:{RESET}{pat}\n")?;
+pub fn err_config(path: &str, error: &ConfigError) -> std::io::Result<std::process::ExitCode> {
+    err(path)?;
+    if error.line_number > 0 {
+        write!(term(), "line {} - ", error.line_number)?;
+    }
+    writeln!(term(), "{RED}{}{RESET}", error.message)?;
     Ok(std::process::ExitCode::FAILURE)
 }
 
-pub fn err_kill_pattern(file_name: &str, line_number: usize, pat: &str) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "kill")?;
-    write!(std::io::stdout(), "{RED}Invalid pattern:{RESET}{pat}\n")?;
+pub fn err_report_create(path: &str) -> std::io::Result<std::process::ExitCode> {
+    err(path)?;
+    writeln!(term(), "{RED}Failed to create report{RESET}")?;
     Ok(std::process::ExitCode::FAILURE)
 }
 
-pub fn err_file_name(file_name: &str, line_number: usize) -> std::io::Result<std::process::ExitCode> {
-    err_line_directive(file_name, line_number, "file")?;
-    write!(std::io::stdout(), "{RED}Missing file name{RESET}\n")?;
+pub fn err_sandbox(path: &str) -> std::io::Result<std::process::ExitCode> {
+    err(path)?;
+    writeln!(term(), "{RED}Failed to copy into sandbox{RESET}")?;
     Ok(std::process::ExitCode::FAILURE)
 }
 
-pub fn err_no_lang(file_name: &str, line_number: usize) -> std::io::Result<std::process::ExitCode> {
-    err_line_code(file_name, line_number)?;
-    write!(std::io::stdout(), "{RED}No language specified{RESET}\n")?;
-    Ok(std::process::ExitCode::FAILURE)
+pub fn err_shell_spawn(file_name: &str, line_number: usize, program: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_code(line_number),
+        &[(RED, "Failed to start shell: "), (ITALIC, program)],
+    )
+}
+
+pub fn err_session_no_name(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("session", line_number),
+        &[(RED, "No session name")],
+    )
+}
+
+pub fn err_deps(file_name: &str, line_number: usize, arg: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("deps", line_number),
+        &[(RED, "Expected NAME=PATH or NAME=VERSION, got "), (ITALIC, arg)],
+    )
+}
+
+pub fn err_extract_no_var(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("extract", line_number),
+        &[(RED, "No variable name")],
+    )
+}
+
+pub fn err_env_no_var(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("env", line_number),
+        &[(RED, "No variable name")],
+    )
+}
+
+pub fn err_env_not_set(file_name: &str, line_number: usize, var: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("env", line_number),
+        &[(RED, "Variable was not set: "), (ITALIC, var)],
+    )
+}
+
+pub fn err_alias_no_var(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("alias", line_number),
+        &[(RED, "No variable name")],
+    )
+}
+
+pub fn err_alias_not_captured(file_name: &str, line_number: usize, var: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("alias", line_number),
+        &[(RED, "Variable has not been previously captured: "), (ITALIC, var)],
+    )
+}
+
+pub fn err_extract_pattern(file_name: &str, line_number: usize, pat: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("extract", line_number),
+        &[(RED, "Invalid pattern: "), (ITALIC, pat)],
+    )
+}
+
+pub fn err_kill_pattern(file_name: &str, line_number: usize, pat: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("kill", line_number),
+        &[(RED, "Invalid pattern: "), (ITALIC, pat)],
+    )
+}
+
+pub fn err_exit_code(file_name: &str, line_number: usize, code: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("exit-code", line_number),
+        &[
+            (RED, "Invalid exit code, expected a number or nonzero: "),
+            (ITALIC, code),
+        ],
+    )
+}
+
+pub fn err_expect_error_pattern(file_name: &str, line_number: usize, pat: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("expect-error", line_number),
+        &[(RED, "Invalid pattern: "), (ITALIC, pat)],
+    )
+}
+
+pub fn err_timeout(file_name: &str, line_number: usize, duration: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("timeout", line_number),
+        &[(RED, "Invalid duration: "), (ITALIC, duration)],
+    )
+}
+
+pub fn err_background_pattern(file_name: &str, line_number: usize, pat: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("background", line_number),
+        &[(RED, "Invalid pattern: "), (ITALIC, pat)],
+    )
+}
+
+pub fn err_prompt_no_answer(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("expect-prompt", line_number),
+        &[(RED, "Missing answer")],
+    )
+}
+
+pub fn err_prompt_pattern(file_name: &str, line_number: usize, pat: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("expect-prompt", line_number),
+        &[(RED, "Invalid pattern: "), (ITALIC, pat)],
+    )
 }
 
-pub fn err_block_close(
+pub fn err_file_name(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("file", line_number),
+        &[(RED, "Missing file name")],
+    )
+}
+
+pub fn err_expect_stream(file_name: &str, line_number: usize, stream: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive("expect", line_number),
+        &[
+            (RED, "Invalid stream, expected stdout, stderr or both: "),
+            (ITALIC, stream),
+        ],
+    )
+}
+
+pub fn err_expect_no_block(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(file_name, at_code(line_number), &[(RED, "No expected output block")])
+}
+
+pub fn err_no_lang(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(file_name, at_code(line_number), &[(RED, "No language specified")])
+}
+
+pub fn err_comment_close(file_name: &str, line_number: usize) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        Some(format!("comment at line {line_number}")),
+        &[(RED, "Unclosed comment, expected a closing "), (RESET, "-->")],
+    )
+}
+
+pub fn err_directive_unknown(
     file_name: &str,
     line_number: usize,
-    delimiter: &str,
-) -> std::io::Result<std::process::ExitCode> {
-    err_line_code(file_name, line_number)?;
-    if !delimiter.is_empty() {
-        write!(
-            std::io::stdout(),
-            "{RED}Invalid closing delimiter:{RESET} {delimiter}\n"
-        )?;
-        Ok(std::process::ExitCode::FAILURE)
-    } else {
-        write!(std::io::stdout(), "{RED}Unclosed block{RESET}\n")?;
-        Ok(std::process::ExitCode::FAILURE)
+    directive: &str,
+    suggestion: Option<&str>,
+) -> std::io::Result<Abort> {
+    let location = at_directive(directive, line_number);
+    match suggestion {
+        Some(suggestion) => abort(
+            file_name,
+            location,
+            &[
+                (RED, "Unknown directive, did you mean "),
+                (ITALIC, suggestion),
+                (RED, "?"),
+            ],
+        ),
+        None => abort(file_name, location, &[(RED, "Unknown directive")]),
     }
 }
 
+pub fn err_directive_dangling(file_name: &str, line_number: usize, directive: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_directive(directive, line_number),
+        &[(RED, "Directive is not followed by any code block")],
+    )
+}
+
+pub fn err_syntax(file_name: &str, error: &SyntaxError) -> std::io::Result<Abort> {
+    let (line_number, reason, text) = match error {
+        SyntaxError::Unclosed { line_number, delimiter } => {
+            (line_number, "Missing closing delimiter: ", delimiter.clone())
+        }
+        SyntaxError::Trailing { line_number, text } => {
+            (line_number, "Unexpected characters after closing quote: ", text.clone())
+        }
+        SyntaxError::Quote { line_number, text } => {
+            (line_number, "Unexpected quote in unquoted argument: ", text.clone())
+        }
+        SyntaxError::Value { line_number, key } => (line_number, "Missing value for option: ", format!("{key}=")),
+    };
+    abort(
+        file_name,
+        Some(format!("directive at line {line_number}")),
+        &[(RED, reason), (RESET, &text)],
+    )
+}
+
+pub fn err_block_close(file_name: &str, line_number: usize, fence: &str) -> std::io::Result<Abort> {
+    abort(
+        file_name,
+        at_code(line_number),
+        &[(RED, "Unclosed block, expected a closing "), (RESET, fence)],
+    )
+}
+
 pub fn err_cmd_capture(
     file_name: &str,
     line_number: usize,
@@ -174,10 +439,11 @@ pub fn err_cmd_capture(
     stderr: &str,
     re: &regex::Regex,
 ) -> std::io::Result<std::process::ExitCode> {
-    err_line_code(file_name, line_number)?;
-    write!(
-        std::io::stdout(),
-        "{RED}Failed to capture matches:{RESET} {ITALIC}\"{re}\"{RESET}\n",
+    let re = format!("\"{re}\"");
+    error(
+        file_name,
+        at_code(line_number),
+        &[(RED, "Failed to capture matches: "), (ITALIC, &re)],
     )?;
 
     log_program(program_and_args, stdout.is_empty() && stderr.is_empty(), RED)?;
@@ -187,14 +453,14 @@ pub fn err_cmd_capture(
     Ok(std::process::ExitCode::FAILURE)
 }
 
-fn log_program(program_and_args: &str, terminate: bool, accent: &str) -> std::io::Result<std::process::ExitCode> {
+fn log_program(program_and_args: &str, terminate: bool, accent: Color) -> std::io::Result<std::process::ExitCode> {
     let program_and_args = program_and_args
         .trim()
         .to_string()
         .replace("\n", &format!("\n{accent}│{RESET} "));
     if !terminate {
         write!(
-            std::io::stdout(),
+            term(),
             "\
                 {accent}│{RESET} ```\n\
                 {accent}│{RESET} {program_and_args}\n\
@@ -203,7 +469,7 @@ fn log_program(program_and_args: &str, terminate: bool, accent: &str) -> std::io
         )?;
     } else {
         write!(
-            std::io::stdout(),
+            term(),
             "\
                 {accent}│{RESET} ```\n\
                 {accent}│{RESET} {program_and_args}\n\
@@ -214,22 +480,22 @@ fn log_program(program_and_args: &str, terminate: bool, accent: &str) -> std::io
     Ok(std::process::ExitCode::FAILURE)
 }
 
-fn log_stdout(mut stdout: String, terminate: bool, accent: &str) -> std::io::Result<std::process::ExitCode> {
+fn log_stdout(mut stdout: String, terminate: bool, accent: Color) -> std::io::Result<std::process::ExitCode> {
     if !stdout.is_empty() {
         stdout = stdout.trim().replace("\n", &format!("\n{accent}│{RESET} >> "));
         if !terminate {
             write!(
-                std::io::stdout(),
+                term(),
                 "\
                     {accent}│{RESET} >> {BOLD}{ITALIC}stdout{RESET}\n\
                     {accent}│{RESET} >>\n\
                     {accent}│{RESET} >> {stdout}\n\
-                    {accent}╰{RESET} >>\n\
+                    {accent}│{RESET} >>\n\
                 ",
             )?;
         } else {
             write!(
-                std::io::stdout(),
+                term(),
                 "\
                     {accent}│{RESET} >> {BOLD}{ITALIC}stdout{RESET}\n\
                     {accent}│{RESET} >>\n\
@@ -243,11 +509,11 @@ fn log_stdout(mut stdout: String, terminate: bool, accent: &str) -> std::io::Res
     Ok(std::process::ExitCode::FAILURE)
 }
 
-fn log_stderr(mut stderr: String, accent: &str) -> std::io::Result<std::process::ExitCode> {
+fn log_stderr(mut stderr: String, accent: Color) -> std::io::Result<std::process::ExitCode> {
     if !stderr.is_empty() {
         stderr = stderr.trim().replace("\n", &format!("\n{accent}│{RESET} >> "));
         write!(
-            std::io::stdout(),
+            term(),
             "\
                 {accent}│{RESET} >> {BOLD}{ITALIC}stderr{RESET}\n\
                 {accent}│{RESET} >>\n\
@@ -0,0 +1,153 @@
+use std::io::Write;
+
+use crate::directive::Directive;
+use crate::summary::{Case, Outcome, Summary};
+
+/// Writes every step of a run to stdout as a stream of JSON objects, one per line, for tools built
+/// on top of `md-command-check`. Nothing is written unless enabled.
+pub struct Events {
+    enabled: bool,
+}
+
+impl Events {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    pub fn file_start(&self, file_name: &str) -> std::io::Result<()> {
+        self.emit(format_args!(r#"{{"event":"file-start","file":{}}}"#, string(file_name)))
+    }
+
+    pub fn file_end(&self, file_name: &str, passed: bool, duration: std::time::Duration) -> std::io::Result<()> {
+        self.emit(format_args!(
+            r#"{{"event":"file-end","file":{},"status":"{}","duration_ms":{}}}"#,
+            string(file_name),
+            if passed { "passed" } else { "failed" },
+            duration.as_millis()
+        ))
+    }
+
+    pub fn directive(&self, file_name: &str, directive: &Directive) -> std::io::Result<()> {
+        let args = directive
+            .args
+            .iter()
+            .map(|arg| string(&arg.to_string()))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.emit(format_args!(
+            r#"{{"event":"directive-parsed","file":{},"line":{},"name":{},"args":[{args}]}}"#,
+            string(file_name),
+            directive.line_number,
+            string(&directive.name)
+        ))
+    }
+
+    pub fn block_start(
+        &self,
+        file_name: &str,
+        line_number: usize,
+        lang: &str,
+        session: Option<&str>,
+        script: &str,
+    ) -> std::io::Result<()> {
+        self.emit(format_args!(
+            r#"{{"event":"block-start","file":{},"line":{line_number},"lang":{},"session":{},"script":{}}}"#,
+            string(file_name),
+            string(lang),
+            session.map_or("null".to_string(), string),
+            string(script)
+        ))
+    }
+
+    /// Output of a running code block, as it is received. Empty chunks are skipped.
+    pub fn output(&self, file_name: &str, line_number: usize, stream: &str, chunk: &str) -> std::io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        self.emit(format_args!(
+            r#"{{"event":"output-chunk","file":{},"line":{line_number},"stream":"{stream}","data":{}}}"#,
+            string(file_name),
+            string(chunk)
+        ))
+    }
+
+    pub fn block_end(&self, case: &Case) -> std::io::Result<()> {
+        let (status, message) = match &case.outcome {
+            Outcome::Passed => ("passed", "null".to_string()),
+            Outcome::Failed(message) => ("failed", string(message)),
+            Outcome::Ignored => ("ignored", "null".to_string()),
+        };
+        let exit_code = case.exit_code.map_or("null".to_string(), |code| code.to_string());
+        self.emit(format_args!(
+            r#"{{"event":"block-end","file":{},"line":{},"status":"{status}","exit_code":{exit_code},"duration_ms":{},"message":{message}}}"#,
+            string(&case.file_name),
+            case.line_number,
+            case.duration.as_millis()
+        ))
+    }
+
+    pub fn variable(&self, file_name: &str, line_number: usize, name: &str, value: &str) -> std::io::Result<()> {
+        self.emit(format_args!(
+            r#"{{"event":"variable-captured","file":{},"line":{line_number},"name":{},"value":{}}}"#,
+            string(file_name),
+            string(name),
+            string(value)
+        ))
+    }
+
+    pub fn file_created(&self, file_name: &str, line_number: usize, path: &str) -> std::io::Result<()> {
+        self.emit(format_args!(
+            r#"{{"event":"file-created","file":{},"line":{line_number},"path":{}}}"#,
+            string(file_name),
+            string(path)
+        ))
+    }
+
+    pub fn teardown(&self, file_name: &str, line_number: usize, cmd: &str) -> std::io::Result<()> {
+        self.emit(format_args!(
+            r#"{{"event":"teardown","file":{},"line":{line_number},"command":{}}}"#,
+            string(file_name),
+            string(cmd)
+        ))
+    }
+
+    pub fn run_end(&self, summary: &Summary) -> std::io::Result<()> {
+        self.emit(format_args!(
+            r#"{{"event":"run-end","passed":{},"failed":{},"ignored":{},"files_created":{},"sandbox":{},"duration_ms":{}}}"#,
+            summary.passed,
+            summary.failures.len(),
+            summary.ignored,
+            summary.files_created,
+            summary.sandbox.as_ref().map_or("null".to_string(), |(path, _)| string(path)),
+            summary.start.elapsed().as_millis()
+        ))
+    }
+
+    fn emit(&self, event: std::fmt::Arguments) -> std::io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mut stdout = crate::draw::machine();
+        writeln!(stdout, "{event}")?;
+        stdout.flush()
+    }
+}
+
+/// Formats text as a JSON string.
+fn string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,218 @@
+/// Output stream(s) an `expect` directive is compared against.
+#[derive(Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    Both,
+}
+
+impl Stream {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+            Stream::Both => "stdout+stderr",
+        }
+    }
+
+    pub fn select(&self, stdout: &str, stderr: &str) -> String {
+        match self {
+            Stream::Stdout => stdout.to_string(),
+            Stream::Stderr => stderr.to_string(),
+            Stream::Both => format!("{stdout}{stderr}"),
+        }
+    }
+}
+
+/// Exit code a code block is expected to terminate with.
+#[derive(Clone, Copy)]
+pub enum Code {
+    Exact(i32),
+    NonZero,
+}
+
+impl Code {
+    pub fn matches(&self, code: i32) -> bool {
+        match self {
+            Code::Exact(n) => *n == code,
+            Code::NonZero => code != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Code::Exact(n) => write!(f, "{n}"),
+            Code::NonZero => write!(f, "nonzero"),
+        }
+    }
+}
+
+/// A single line in the diff between the expected and the actual output of a code block.
+pub enum Diff<'a> {
+    Same(&'a str),
+    Expected(&'a str),
+    Actual(&'a str),
+}
+
+const ELLIPSIS: &str = "...";
+
+/// Compares the `actual` output of a code block to its `expected` output.
+///
+/// Comparison is done line by line, ignoring trailing whitespace. Similarly to doctests, `...` can
+/// be used as a wildcard: inside of a line it matches any text, and on a line of its own it matches
+/// any number of lines (including none).
+///
+/// Returns `None` if the output matches, or a line diff otherwise.
+pub fn diff<'a>(expected: &'a str, actual: &'a str) -> Option<Vec<Diff<'a>>> {
+    let expected = lines(expected);
+    let actual = lines(actual);
+
+    // cost[i][j] is the minimum number of differing lines when aligning `expected[i..]` with
+    // `actual[j..]`
+    let mut cost = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..=expected.len()).rev() {
+        for j in (0..=actual.len()).rev() {
+            cost[i][j] = if i == expected.len() {
+                actual.len() - j
+            } else if j == actual.len() {
+                if expected[i] == ELLIPSIS {
+                    cost[i + 1][j]
+                } else {
+                    cost[i + 1][j] + 1
+                }
+            } else if expected[i] == ELLIPSIS {
+                cost[i + 1][j].min(cost[i][j + 1])
+            } else if matches_line(expected[i], actual[j]) {
+                cost[i + 1][j + 1]
+            } else {
+                (cost[i + 1][j] + 1).min(cost[i][j + 1] + 1)
+            };
+        }
+    }
+
+    if cost[0][0] == 0 {
+        return None;
+    }
+
+    let mut diff = Vec::with_capacity(expected.len() + actual.len());
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && expected[i] == ELLIPSIS {
+            if j < actual.len() && cost[i][j] == cost[i][j + 1] {
+                diff.push(Diff::Same(actual[j]));
+                j += 1;
+            } else {
+                i += 1;
+            }
+        } else if i < expected.len() && j < actual.len() && matches_line(expected[i], actual[j]) {
+            diff.push(Diff::Same(actual[j]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && cost[i][j] == cost[i + 1][j] + 1) {
+            diff.push(Diff::Expected(expected[i]));
+            i += 1;
+        } else {
+            diff.push(Diff::Actual(actual[j]));
+            j += 1;
+        }
+    }
+
+    Some(diff)
+}
+
+fn lines(s: &str) -> Vec<&str> {
+    s.trim_end().lines().map(str::trim_end).collect()
+}
+
+/// Matches a single line against an expected line which may contain `...` wildcards.
+fn matches_line(expected: &str, actual: &str) -> bool {
+    let mut parts = expected.split(ELLIPSIS);
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = actual.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    let Some(stripped) = rest.strip_suffix(last) else {
+        return false;
+    };
+    rest = stripped;
+
+    for part in parts {
+        let Some(n) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[n + part.len()..];
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines of a diff, prefixed like in a unified diff.
+    fn lines_of(expected: &str, actual: &str) -> Option<Vec<String>> {
+        let diff = diff(expected, actual)?;
+        let lines = diff.into_iter().map(|line| match line {
+            Diff::Same(line) => format!(" {line}"),
+            Diff::Expected(line) => format!("-{line}"),
+            Diff::Actual(line) => format!("+{line}"),
+        });
+        Some(lines.collect())
+    }
+
+    #[test]
+    fn exact() {
+        assert!(diff("a\nb\n", "a\nb").is_none());
+        assert!(diff("a  \nb\n\n", "a\nb  ").is_none());
+        assert_eq!(
+            lines_of("a\nb\nc", "a\nx\nc"),
+            Some(vec![" a".into(), "-b".into(), "+x".into(), " c".into()])
+        );
+    }
+
+    #[test]
+    fn empty_output() {
+        assert!(diff("", "").is_none());
+        assert!(diff("\n", "  \n").is_none());
+        assert!(diff("...", "").is_none());
+        assert_eq!(lines_of("a", ""), Some(vec!["-a".into()]));
+        assert_eq!(lines_of("", "a"), Some(vec!["+a".into()]));
+    }
+
+    #[test]
+    fn ellipsis_lines() {
+        // Leading, trailing and middle lines of their own match any number of lines
+        assert!(diff("...\nlast", "a\nb\nlast").is_none());
+        assert!(diff("first\n...", "first\na\nb").is_none());
+        assert!(diff("first\n...", "first").is_none());
+        assert!(diff("first\n...\nlast", "first\nlast").is_none());
+        assert!(diff("...\nmiddle\n...", "a\nmiddle\nb").is_none());
+
+        assert_eq!(
+            lines_of("...\nlast", "a\nother"),
+            Some(vec![" a".into(), " other".into(), "-last".into()])
+        );
+        assert_eq!(
+            lines_of("first\n...", "other\na"),
+            Some(vec!["-first".into(), " other".into(), " a".into()])
+        );
+    }
+
+    #[test]
+    fn ellipsis_in_lines() {
+        assert!(diff("took ...s", "took 1.5s").is_none());
+        assert!(diff("...done", "all done").is_none());
+        assert!(diff("id: ...", "id: 42").is_none());
+        assert!(diff("a...b...c", "a1b2c").is_none());
+        assert!(diff("a...b...c", "a1c2b").is_some());
+        assert!(diff("ab...ba", "aba").is_some());
+    }
+}
@@ -0,0 +1,160 @@
+//! Writes code blocks out as a shell script, so that they can be run without `md-command-check`.
+//!
+//! Capture variables such as `<VAR>` are written as shell variables. Directives which control how
+//! code blocks are run or checked, such as `expect`, `kill` or `timeout`, are left out.
+
+use crate::rust::Check;
+
+pub fn header(out: &mut impl std::io::Write) -> std::io::Result<()> {
+    writeln!(out, "#!/usr/bin/env bash")?;
+    writeln!(out, "# Generated by md-command-check")
+}
+
+pub fn file_start(out: &mut impl std::io::Write, file_name: &str) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "# {file_name}")
+}
+
+/// Writes a code block, which is passed to its interpreter if it is not run by the shell.
+pub fn block(
+    out: &mut impl std::io::Write,
+    file_name: &str,
+    line_number: usize,
+    script: &str,
+    interpreter: Option<&str>,
+    background: bool,
+) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "# {file_name}:{line_number}")?;
+    if background {
+        writeln!(out, "{{")?;
+    }
+    match interpreter {
+        Some(interpreter) => {
+            let delimiter = delimiter(script);
+            writeln!(out, "{interpreter} <(cat <<'{delimiter}'")?;
+            heredoc(out, script)?;
+            writeln!(out, "{delimiter}")?;
+            writeln!(out, ")")?;
+        }
+        None => writeln!(out, "{}", script.trim_end())?,
+    }
+    if background {
+        writeln!(out, "}} &")?;
+    }
+    Ok(())
+}
+
+/// Writes a `rust` code block as a scratch cargo project, which is then built and run.
+#[allow(clippy::too_many_arguments)]
+pub fn rust(
+    out: &mut impl std::io::Write,
+    file_name: &str,
+    line_number: usize,
+    cargo: &str,
+    name: &str,
+    manifest: &str,
+    source: &str,
+    check: Check,
+    background: bool,
+) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "# {file_name}:{line_number}")?;
+    if background {
+        writeln!(out, "{{")?;
+    }
+    writeln!(out, "project=$(mktemp -d)")?;
+    writeln!(out, "mkdir \"$project/src\"")?;
+    for (path, content) in [("Cargo.toml", manifest), ("src/main.rs", source)] {
+        let delimiter = delimiter(content);
+        writeln!(out, "cat > \"$project/{path}\" <<'{delimiter}'")?;
+        heredoc(out, content)?;
+        writeln!(out, "{delimiter}")?;
+    }
+    let command = crate::rust::command(
+        cargo,
+        name,
+        "\"$project/Cargo.toml\"",
+        "\"${CARGO_TARGET_DIR:-$project/target}\"",
+        check,
+    );
+    writeln!(out, "{command}")?;
+    if background {
+        writeln!(out, "}} &")?;
+    }
+    Ok(())
+}
+
+pub fn file(
+    out: &mut impl std::io::Write,
+    file_name: &str,
+    line_number: usize,
+    path: &str,
+    content: &str,
+) -> std::io::Result<()> {
+    let delimiter = delimiter(content);
+    writeln!(out)?;
+    writeln!(out, "# {file_name}:{line_number}")?;
+    writeln!(out, "cat > {} <<'{delimiter}'", quote(path))?;
+    heredoc(out, content)?;
+    writeln!(out, "{delimiter}")
+}
+
+/// Declares a variable holding the value of an environment variable or of another variable.
+pub fn variable(
+    out: &mut impl std::io::Write,
+    file_name: &str,
+    line_number: usize,
+    var: &str,
+    value: &str,
+) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "# {file_name}:{line_number}")?;
+    writeln!(out, "{var}=\"${{{value}}}\"")
+}
+
+/// Captured variables cannot be extracted from the output of a code block, so they have to be set
+/// by hand.
+pub fn extract(
+    out: &mut impl std::io::Write,
+    file_name: &str,
+    line_number: usize,
+    var: &str,
+    pat: &str,
+) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "# {file_name}:{line_number}")?;
+    writeln!(
+        out,
+        "# {var} is captured from the output of the next code block with \"{pat}\""
+    )
+}
+
+pub fn teardown(out: &mut impl std::io::Write, file_name: &str, line_number: usize, cmd: &str) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "# {file_name}:{line_number} (teardown)")?;
+    writeln!(out, "{cmd}")
+}
+
+/// The delimiter of a here-document must not appear in its content.
+fn delimiter(content: &str) -> String {
+    let mut delimiter = "EOF".to_string();
+    while content.lines().any(|line| line == delimiter) {
+        delimiter.push('_');
+    }
+    delimiter
+}
+
+/// Writes the content of a here-document, which has to end with a line break.
+fn heredoc(out: &mut impl std::io::Write, content: &str) -> std::io::Result<()> {
+    write!(out, "{content}")?;
+    if !content.is_empty() && !content.ends_with('\n') {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Quotes a string for use as a single shell word.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
@@ -0,0 +1,430 @@
+//! Resolves the paths given on the command line into a list of Markdown files.
+//!
+//! Paths can be files, directories or glob patterns such as `docs/**/*.md`. Directories are searched
+//! recursively, skipping hidden files as well as files ignored by `.gitignore`.
+
+/// Extensions of Markdown files.
+pub const EXTENSIONS: &[&str] = &["md", "markdown", "mdx"];
+
+/// A path which could not be resolved.
+pub enum Unresolved {
+    /// A directory or glob pattern which does not hold any Markdown file.
+    Empty { path: String },
+    /// A directory which could not be read.
+    Read { path: String },
+}
+
+/// A pattern from a `.gitignore` file.
+struct Rule {
+    /// Directory holding the `.gitignore` file, which paths are matched relative to.
+    base: std::path::PathBuf,
+    regex: regex::Regex,
+    negate: bool,
+    dir_only: bool,
+}
+
+/// Resolves paths into a list of Markdown files, in order and without duplicates. Files found in a
+/// directory or through a glob pattern are sorted by path, with numbers in numeric order.
+///
+/// Only files which match an `include` pattern, if any, and which do not match any `exclude`
+/// pattern are kept.
+pub fn resolve(paths: &[String], include: &[String], exclude: &[String]) -> Result<Vec<String>, Unresolved> {
+    let include = include.iter().map(|pattern| filter(pattern)).collect::<Vec<_>>();
+    let exclude = exclude.iter().map(|pattern| filter(pattern)).collect::<Vec<_>>();
+    let keep = |file: &str| {
+        (include.is_empty() || include.iter().any(|regex| regex.is_match(file)))
+            && !exclude.iter().any(|regex| regex.is_match(file))
+    };
+
+    // The same file can be given through different paths
+    let mut seen = std::collections::HashSet::new();
+    let mut files = Vec::<String>::new();
+    let mut push = |file: String| {
+        let canonical = std::fs::canonicalize(&file).unwrap_or_else(|_| file.clone().into());
+        if seen.insert(canonical) {
+            files.push(file);
+        }
+    };
+
+    for path in paths {
+        let mut found = if std::path::Path::new(path).is_dir() {
+            walk(path)?
+        } else if !std::path::Path::new(path).exists() && path.contains(['*', '?', '[']) {
+            // Only directories without any special character need to be searched
+            let pattern = path.trim_start_matches("./");
+            let n = pattern.find(['*', '?', '[']).unwrap_or_default();
+            let base = pattern[..n].rfind('/').map_or("", |slash| &pattern[..=slash]);
+            let regex = regex::Regex::new(&format!("^{}$", glob(pattern))).expect("Glob patterns are escaped");
+            walk(base)?.into_iter().filter(|file| regex.is_match(file)).collect()
+        } else {
+            // Files are kept as is and checked once they are run
+            if keep(path) {
+                push(path.clone());
+            }
+            continue;
+        };
+
+        found.retain(|file| keep(file));
+        if found.is_empty() {
+            return Err(Unresolved::Empty { path: path.clone() });
+        }
+
+        found.sort_by_cached_key(|file| sort_key(file));
+        found.into_iter().for_each(&mut push);
+    }
+
+    Ok(files)
+}
+
+/// Groups files into the chains they belong to, if any, so that files which depend on each other are
+/// run one after the other. Files in a chain are run in the order of the chain, from the position of
+/// its first file. Every other file is a group of its own.
+pub fn group(files: &[String], chains: &[Vec<String>]) -> Result<Vec<Vec<String>>, Unresolved> {
+    let canonical = |file: &String| std::fs::canonicalize(file).unwrap_or_else(|_| file.into());
+    let known = files.iter().map(canonical).collect::<Vec<_>>();
+
+    // Files of a chain which are not run are left out of it
+    let mut chained = Vec::new();
+    for chain in chains {
+        let chain = resolve(chain, &[], &[])?
+            .iter()
+            .filter_map(|file| known.iter().position(|known| *known == canonical(file)))
+            .collect::<Vec<_>>();
+        chained.push(chain);
+    }
+
+    let mut grouped = vec![false; files.len()];
+    let mut groups = Vec::new();
+    for n in 0..files.len() {
+        if grouped[n] {
+            continue;
+        }
+        let chain = match chained.iter().find(|chain| chain.contains(&n)) {
+            Some(chain) => chain.iter().copied().filter(|n| !grouped[*n]).collect(),
+            None => vec![n],
+        };
+        for n in chain.iter() {
+            grouped[*n] = true;
+        }
+        groups.push(chain.into_iter().map(|n| files[n].clone()).collect());
+    }
+
+    Ok(groups)
+}
+
+/// Whether a file has a Markdown extension.
+pub fn is_markdown(path: &std::path::Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| EXTENSIONS.iter().any(|known| ext == *known))
+}
+
+/// Lists every Markdown file in a directory and its subdirectories. Paths in the current directory
+/// are not prefixed with `./`.
+fn walk(dir: &str) -> Result<Vec<String>, Unresolved> {
+    let dir = if dir == "." || dir == "./" { "" } else { dir };
+    let Ok(abs) = std::fs::canonicalize(if dir.is_empty() { "." } else { dir }) else {
+        return Err(Unresolved::Read { path: dir.to_string() });
+    };
+
+    // `.gitignore` files from parent directories also apply, up to the root of the repository
+    let mut rules = Vec::new();
+    let parents = abs.ancestors().skip(1).collect::<Vec<_>>();
+    if let Some(top) = parents.iter().position(|parent| parent.join(".git").exists()) {
+        for parent in parents[..=top].iter().rev() {
+            gitignore(parent, &mut rules);
+        }
+    }
+
+    let mut files = Vec::new();
+    walk_dir(dir, &abs, &mut rules, &mut files)?;
+    Ok(files)
+}
+
+fn walk_dir(
+    dir: &str,
+    abs: &std::path::Path,
+    rules: &mut Vec<Rule>,
+    files: &mut Vec<String>,
+) -> Result<(), Unresolved> {
+    let Ok(entries) = std::fs::read_dir(abs) else {
+        return Err(Unresolved::Read { path: dir.to_string() });
+    };
+
+    // Rules from this directory only apply to its content
+    let len = rules.len();
+    gitignore(abs, rules);
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let path = if dir.is_empty() || dir.ends_with('/') {
+            format!("{dir}{name}")
+        } else {
+            format!("{dir}/{name}")
+        };
+        let abs = abs.join(&name);
+
+        // Symbolic links to directories are not followed, to avoid going around in circles
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let is_dir = file_type.is_dir();
+        if is_ignored(&abs, is_dir, rules) {
+            continue;
+        }
+
+        if is_dir {
+            walk_dir(&path, &abs, rules, files)?;
+        } else if abs.is_file() && is_markdown(&abs) {
+            files.push(path);
+        }
+    }
+
+    rules.truncate(len);
+    Ok(())
+}
+
+/// Reads the rules of the `.gitignore` file in a directory, if any.
+fn gitignore(dir: &std::path::Path, rules: &mut Vec<Rule>) {
+    let Ok(content) = std::fs::read_to_string(dir.join(".gitignore")) else {
+        return;
+    };
+
+    for line in content.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+
+        // Patterns with a slash are relative to the `.gitignore` file, others match at any depth
+        let pattern = match line.strip_prefix('/') {
+            Some(line) => format!("^{}$", glob(line)),
+            None if line.contains('/') => format!("^{}$", glob(line)),
+            None => format!("^(?:.*/)?{}$", glob(line)),
+        };
+
+        rules.push(Rule {
+            base: dir.to_path_buf(),
+            regex: regex::Regex::new(&pattern).expect("Glob patterns are escaped"),
+            negate,
+            dir_only,
+        });
+    }
+}
+
+/// Whether a path is ignored, in which case later rules take precedence over earlier ones.
+fn is_ignored(abs: &std::path::Path, is_dir: bool, rules: &[Rule]) -> bool {
+    let mut ignored = false;
+    for rule in rules.iter().filter(|rule| is_dir || !rule.dir_only) {
+        if let Ok(path) = abs.strip_prefix(&rule.base)
+            && rule.regex.is_match(&path.to_string_lossy())
+        {
+            ignored = !rule.negate;
+        }
+    }
+    ignored
+}
+
+/// Include and exclude patterns match the end of a path, so that `drafts/*.md` matches files in
+/// any `drafts` directory.
+fn filter(pattern: &str) -> regex::Regex {
+    let pattern = pattern.trim_start_matches("./");
+    regex::Regex::new(&format!("^(?:.*/)?{}$", glob(pattern))).expect("Glob patterns are escaped")
+}
+
+/// Converts a glob pattern into a regex. `*` and `?` do not match `/`, unlike `**`.
+fn glob(pattern: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = pattern.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '*' if pattern[i..].starts_with("**/") => {
+                regex.push_str("(?:.*/)?");
+                chars.next();
+                chars.next();
+            }
+            '*' if pattern[i..].starts_with("**") => {
+                regex.push_str(".*");
+                chars.next();
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            // Brackets without a closing bracket are kept as is
+            '[' => match pattern[i + 1..].find(']').filter(|end| *end > 0) {
+                Some(end) => {
+                    let class = &pattern[i + 1..i + 1 + end];
+                    let (negate, class) = match class.strip_prefix('!') {
+                        Some(class) => ("^", class),
+                        None => ("", class),
+                    };
+                    let class = class.replace('\\', r"\\").replace('[', r"\[");
+                    regex.push_str(&format!("[{negate}{class}]"));
+                    for _ in 0..=end {
+                        chars.next();
+                    }
+                }
+                None => regex.push_str(r"\["),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex
+}
+
+/// Part of a file name, so that numbers can be compared by value.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Chunk {
+    /// Digits without leading zeros, preceded by their count.
+    Number(usize, String),
+    Text(String),
+}
+
+/// Sorts paths one directory at a time, so that `2-install.md` comes before `10-usage.md`. Paths
+/// which only differ by leading zeros are sorted as is.
+fn sort_key(path: &str) -> (Vec<Vec<Chunk>>, String) {
+    let components = path
+        .split('/')
+        .map(|component| {
+            let mut chunks = Vec::new();
+            let mut rest = component;
+            while let Some(c) = rest.chars().next() {
+                let n = rest
+                    .find(|d: char| d.is_ascii_digit() != c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let (chunk, next) = rest.split_at(n);
+                if c.is_ascii_digit() {
+                    let digits = chunk.trim_start_matches('0');
+                    chunks.push(Chunk::Number(digits.len(), digits.to_string()));
+                } else {
+                    chunks.push(Chunk::Text(chunk.to_string()));
+                }
+                rest = next;
+            }
+            chunks
+        })
+        .collect();
+
+    (components, path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        regex::Regex::new(&format!("^{}$", glob(pattern)))
+            .unwrap()
+            .is_match(path)
+    }
+
+    /// Reads a `.gitignore` file written to a directory of its own.
+    fn rules(name: &str, content: &str) -> (std::path::PathBuf, Vec<Rule>) {
+        let dir = std::env::temp_dir().join(format!("md-command-check-files-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(".gitignore"), content).unwrap();
+
+        let mut rules = Vec::new();
+        gitignore(&dir, &mut rules);
+        std::fs::remove_dir_all(&dir).unwrap();
+        (dir, rules)
+    }
+
+    #[test]
+    fn globs() {
+        assert!(matches("*.md", "README.md"));
+        assert!(!matches("*.md", "docs/README.md"));
+        assert!(matches("docs/?.md", "docs/a.md"));
+        assert!(!matches("docs/?.md", "docs/ab.md"));
+        assert!(matches("[ab].md", "a.md"));
+        assert!(!matches("[!ab].md", "a.md"));
+        assert!(matches("[.md", "[.md"));
+        assert!(matches("a+b(1).md", "a+b(1).md"));
+    }
+
+    #[test]
+    fn double_stars() {
+        assert!(matches("docs/**/*.md", "docs/a.md"));
+        assert!(matches("docs/**/*.md", "docs/guide/install/a.md"));
+        assert!(!matches("docs/**/*.md", "other/a.md"));
+        assert!(matches("**/a.md", "a.md"));
+        assert!(matches("**/a.md", "docs/a.md"));
+        assert!(matches("docs/**", "docs/guide/a.md"));
+    }
+
+    #[test]
+    fn filters() {
+        assert!(filter("drafts/*.md").is_match("docs/drafts/a.md"));
+        assert!(filter("./drafts/*.md").is_match("drafts/a.md"));
+        assert!(!filter("drafts/*.md").is_match("old-drafts/a.md"));
+        assert!(!filter("drafts/*.md").is_match("drafts/guide/a.md"));
+    }
+
+    #[test]
+    fn gitignore_rules() {
+        let content = "# comment\n\n*.md\n!keep.md\n\\!bang.md\nbuild/\n/root.txt\ndocs/*.txt\n";
+        let (dir, rules) = rules("rules", content);
+        let ignored = |path: &str, is_dir: bool| is_ignored(&dir.join(path), is_dir, &rules);
+
+        // Later rules take precedence, so negated rules bring files back
+        assert!(ignored("a.md", false));
+        assert!(ignored("docs/a.md", false));
+        assert!(!ignored("keep.md", false));
+        assert!(!ignored("docs/keep.md", false));
+        assert!(ignored("!bang.md", false));
+
+        // Directory rules do not apply to files
+        assert!(ignored("build", true));
+        assert!(ignored("docs/build", true));
+        assert!(!ignored("build", false));
+
+        // Rules with a slash are relative to the `.gitignore` file
+        assert!(ignored("root.txt", false));
+        assert!(!ignored("other/root.txt", false));
+        assert!(ignored("docs/a.txt", false));
+        assert!(!ignored("other/docs/a.txt", false));
+        assert!(!ignored("a.txt", false));
+    }
+
+    #[test]
+    fn sort_keys() {
+        let mut files = [
+            "10-usage.md",
+            "2-install.md",
+            "1-intro.md",
+            "docs/10/a.md",
+            "docs/9/b.md",
+            "02-b.md",
+        ];
+        files.sort_by_cached_key(|file| sort_key(file));
+        assert_eq!(
+            files,
+            [
+                "1-intro.md",
+                "02-b.md",
+                "2-install.md",
+                "10-usage.md",
+                "docs/9/b.md",
+                "docs/10/a.md"
+            ]
+        );
+
+        // Leading zeros only break ties
+        let mut files = ["v2.md", "v02.md", "v002.md"];
+        files.sort_by_cached_key(|file| sort_key(file));
+        assert_eq!(files, ["v002.md", "v02.md", "v2.md"]);
+    }
+}
@@ -1,417 +1,1347 @@
+mod cli;
 mod colors;
+mod config;
+mod directive;
 mod draw;
 mod err;
-
-use std::io::{BufRead as _, Write};
-
+mod events;
+mod expect;
+mod export;
+mod files;
+mod markdown;
+mod report;
+mod runner;
+mod rust;
+mod sandbox;
+mod shell;
+mod summary;
+
+use std::io::{IsTerminal, Write};
+
+use cli::{Command, FailFast, Format, Options};
 use colors::*;
+use directive::Arg;
 use draw::*;
 use err::*;
+use events::Events;
+use expect::{Code, Diff, Stream};
+use markdown::{Event, Unclosed};
+use runner::Kind;
+use sandbox::Sandbox;
+use shell::{Background, Chunk, Mode, Script, Shell};
+use summary::{Case, Outcome, Summary};
+
+/// State carried over from one file to the next.
+struct Context {
+    // capture variables
+    vars: std::collections::HashMap<String, String>,
+
+    // manual output
+    out: Vec<u8>,
+
+    // Long-running shell processes, by session and program. We spawn our commands in here. These
+    // are only spawned when needed, and code blocks without a `session` directive share the
+    // unnamed session.
+    shells: std::collections::HashMap<(Option<String>, String), Shell>,
+
+    // Shells running inside of a pseudo-terminal, for code blocks with a `pty` directive
+    shells_pty: std::collections::HashMap<(Option<String>, String), Shell>,
+
+    // Code blocks running in the background, which are stopped at the end of the file
+    background: Vec<Background>,
+
+    // Code blocks which are run as scripts or cargo projects, which are removed at the end of the
+    // file since they might still be running in the background
+    scripts: Vec<Script>,
+    projects: Vec<rust::Project>,
+
+    // Cargo target directory shared by every `rust` code block. This is only created when needed.
+    target: Option<rust::Target>,
+
+    // Directory which shells are started in and files are created in, when running in a sandbox
+    workspace: Option<std::path::PathBuf>,
+
+    summary: Summary,
+
+    events: Events,
+
+    // Whether TAP results are written as soon as they are known. Files run in parallel are reported
+    // once they are done instead, so that results are numbered in order.
+    tap: bool,
+}
+
+impl Context {
+    fn new(options: &Options) -> Self {
+        Self {
+            vars: std::collections::HashMap::new(),
+            out: Vec::with_capacity(8192), // 8kb
+            shells: std::collections::HashMap::new(),
+            shells_pty: std::collections::HashMap::new(),
+            background: Vec::new(),
+            scripts: Vec::new(),
+            projects: Vec::new(),
+            target: None,
+            workspace: None,
+            summary: Summary::new(),
+            events: Events::new(options.format == Format::Jsonl && options.command == Command::Run),
+            tap: options.format == Format::Tap,
+        }
+    }
+
+    fn kill(&mut self) -> std::io::Result<()> {
+        for shell in self.shells.values_mut().chain(self.shells_pty.values_mut()) {
+            shell.kill()?;
+        }
+        Ok(())
+    }
+}
 
 fn main() -> std::io::Result<std::process::ExitCode> {
-    let mut args = std::env::args().skip(1).peekable();
+    // Invalid arguments are reported according to the environment. See https://no-color.org
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|var| !var.is_empty());
+    set_colors(std::io::stdout().is_terminal() && !no_color);
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut options = Options::default();
+    if let Some(path) = config::find(&args)
+        && let Err(error) = config::load(&path, &mut options)
+    {
+        return err_config(&path.to_string_lossy(), &error);
+    }
 
-    // options
-    let mut debug = false;
-    let mut list = false;
+    let mut options = match cli::parse(args, options) {
+        Ok(options) => options,
+        Err(error) => return err_cli(&error),
+    };
 
-    while args.peek().is_some_and(|arg| arg.starts_with("--")) {
-        match args.next().expect("Checked above").as_ref() {
-            "--debug" => debug = true,
-            "--list" => list = true,
-            _ => {}
+    match options.command {
+        Command::Help => {
+            writeln!(std::io::stdout(), "{}", cli::HELP)?;
+            return Ok(std::process::ExitCode::SUCCESS);
         }
+        Command::Version => {
+            writeln!(std::io::stdout(), "md-command-check {}", env!("CARGO_PKG_VERSION"))?;
+            return Ok(std::process::ExitCode::SUCCESS);
+        }
+        _ => {}
     }
 
-    // readline buffers
-    let mut line = String::with_capacity(256);
-    let mut cmd = String::with_capacity(256);
+    // Exported scripts are written to stdout as well
+    if options.format != Format::Pretty && options.command == Command::Run || options.command == Command::Export {
+        set_machine_output();
+    }
 
-    // capture variables
-    let mut vars = std::collections::HashMap::<String, String>::new();
+    // Messages are written to stderr when stdout is used for machine-readable output
+    let terminal = if is_machine_output() {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    options.interactive = options.progress.unwrap_or(terminal);
+    set_colors(options.color.unwrap_or(terminal && !no_color));
+
+    options.files = match files::resolve(&options.files, &options.include, &options.exclude) {
+        Ok(files) => files,
+        Err(unresolved) => return err_unresolved(&unresolved),
+    };
+    let groups = match files::group(&options.files, &options.chains) {
+        Ok(groups) => groups,
+        Err(unresolved) => return err_unresolved(&unresolved),
+    };
+
+    // Reports are created upfront so that an invalid path is caught before running anything. Nothing
+    // is reported when listing code blocks.
+    let mut junit = match options.junit.as_deref().filter(|_| options.command == Command::Run) {
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => Some(std::io::BufWriter::new(file)),
+            Err(_) => return err_report_create(path),
+        },
+        None => None,
+    };
+
+    if options.format == Format::Tap && options.command == Command::Run {
+        writeln!(std::io::stdout(), "TAP version 13")?;
+    } else if options.command == Command::Export {
+        export::header(&mut std::io::stdout())?;
+    }
 
-    // manual output
-    let mut out = Vec::with_capacity(8192); // 8kb
-
-    // Long-running shell process. We spawn our commands in here
-    let mut shell = std::process::Command::new("sh")
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
-
-    let mut cmd_stdin = shell.stdin.take().unwrap();
-    let mut cmd_stdout = std::io::BufReader::new(shell.stdout.take().unwrap());
-    let mut cmd_stderr = std::io::BufReader::new(shell.stderr.take().unwrap());
-
-    while let Some(file_name) = args.next() {
-        let path = std::path::PathBuf::from(&file_name);
-        if !path.extension().is_some_and(|ext| ext == "md") {
-            return err_file_ext(&file_name);
+    // Workspaces are seeded upfront so that a missing path is caught before running anything. Files
+    // run in parallel each get their own workspace.
+    let jobs = options.jobs.filter(|_| options.command == Command::Run);
+    let sandbox = match options.sandbox && options.command == Command::Run {
+        true => Some(Sandbox::new(options.keep_sandbox)?),
+        false => None,
+    };
+    let mut workspaces = Vec::new();
+    if let Some(sandbox) = sandbox.as_ref() {
+        let names = match jobs {
+            Some(_) => groups
+                .iter()
+                .enumerate()
+                .map(|(n, group)| Some(workspace_name(n, group)))
+                .collect(),
+            None => vec![None],
+        };
+        for name in names {
+            match sandbox.workspace(name.as_deref(), &options.seed) {
+                Ok(dir) => workspaces.push(dir),
+                Err(path) => return err_sandbox(&path),
+            }
+        }
+    }
+
+    // Dropped after the context, once every shell has been stopped
+    let _temp_files = shell::TempFiles;
+    let mut ctx = Context::new(&options);
+    ctx.summary.sandbox = sandbox
+        .as_ref()
+        .map(|sandbox| (sandbox.path.to_string_lossy().to_string(), sandbox.keep));
+    match jobs {
+        Some(jobs) => run_parallel(&groups, &workspaces, jobs, &options, &mut ctx)?,
+        None => {
+            ctx.workspace = workspaces.pop();
+            run_files(&groups.concat(), &options, &mut ctx)?;
+            ctx.kill()?;
+        }
+    }
+
+    if options.command != Command::Run {
+        if ctx.summary.failures.is_empty() {
+            return Ok(std::process::ExitCode::SUCCESS);
+        } else {
+            return Ok(std::process::ExitCode::FAILURE);
         }
+    }
+
+    draw_summary(&mut ctx.out, &ctx.summary)?;
+    flush(&mut ctx.out)?;
+
+    if let Some(junit) = junit.as_mut() {
+        report::junit(junit, &ctx.summary)?;
+    }
+
+    if options.format == Format::Tap {
+        report::tap_plan(&mut std::io::stdout(), ctx.summary.reported())?;
+    }
+    ctx.events.run_end(&ctx.summary)?;
+
+    if ctx.summary.failures.is_empty() {
+        Ok(std::process::ExitCode::SUCCESS)
+    } else {
+        Ok(std::process::ExitCode::FAILURE)
+    }
+}
+
+/// Runs files one after the other, sharing shells and variables. Returns whether the run was
+/// stopped because of a failure.
+fn run_files(files: &[String], options: &Options, ctx: &mut Context) -> std::io::Result<bool> {
+    for file_name in files.iter() {
+        let start = std::time::Instant::now();
+        ctx.events.file_start(file_name)?;
+        if options.command == Command::Export {
+            export::file_start(&mut std::io::stdout(), file_name)?;
+        }
+        let ran = run_file(file_name, options, ctx)?;
+        let passed = matches!(ran, Ran::Passed);
+
+        // Background code blocks are stopped even if the file failed
+        for bg in ctx.background.drain(..) {
+            stopped(file_name, bg.line_number, &bg.output(), options.debug)?;
+            bg.stop()?;
+        }
+        ctx.scripts.clear();
+        ctx.projects.clear();
+
+        ctx.summary.files.push((file_name.clone(), start.elapsed()));
+        ctx.events.file_end(file_name, passed, start.elapsed())?;
+        if !passed {
+            let number = ctx.summary.reported() + 1;
+            if let Ran::Aborted(abort) = ran
+                && ctx.summary.abort(file_name, abort.message)
+                && ctx.tap
+                && let Some(failure) = ctx.summary.failures.last()
+            {
+                report::tap_abort(&mut std::io::stdout(), number, failure)?;
+            }
+            // Every file is checked regardless of failures
+            if options.fail_fast == FailFast::All && options.command == Command::Run {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
 
-        let Ok(file) = std::fs::File::open(&path) else {
-            return err_file_open(&file_name);
+/// Runs groups of files on separate threads, each group with its own shells and variables. The
+/// output of each group is held back until it is done, then written in the order of the files.
+fn run_parallel(
+    groups: &[Vec<String>],
+    workspaces: &[std::path::PathBuf],
+    jobs: usize,
+    options: &Options,
+    ctx: &mut Context,
+) -> std::io::Result<()> {
+    // Code blocks are drawn once they are done, since several of them run at the same time
+    let options = &Options {
+        interactive: false,
+        ..options.clone()
+    };
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(groups.len()) {
+            let tx = tx.clone();
+            let (next, stop) = (&next, &stop);
+            scope.spawn(move || {
+                set_buffered();
+                // Groups are started in order, so that every group before a finished one is
+                // eventually finished as well
+                while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                    let n = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    let Some(group) = groups.get(n) else {
+                        break;
+                    };
+
+                    let mut ctx = Context::new(options);
+                    ctx.tap = false;
+                    ctx.workspace = workspaces.get(n).cloned();
+                    let result = run_files(group, options, &mut ctx).and_then(|stopped| {
+                        ctx.kill()?;
+                        Ok(stopped)
+                    });
+                    if !matches!(result, Ok(false)) {
+                        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    let _ = tx.send((n, result.map(|_| (ctx.summary, take_buffer()))));
+                }
+            });
+        }
+        drop(tx);
+
+        let mut done = groups.iter().map(|_| None).collect::<Vec<_>>();
+        let mut written = 0;
+        for (n, result) in rx {
+            done[n] = Some(result);
+            while let Some(result) = done.get_mut(written).and_then(Option::take) {
+                let (summary, buffer) = match result {
+                    Ok(done) => done,
+                    Err(error) => {
+                        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+                        return Err(error);
+                    }
+                };
+
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&buffer.stdout)?;
+                stdout.flush()?;
+                std::io::stderr().write_all(&buffer.stderr)?;
+                merge(summary, ctx)?;
+                written += 1;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Name of the workspace of a group of files run in parallel, after its first file.
+fn workspace_name(n: usize, group: &[String]) -> String {
+    let stem = group
+        .first()
+        .and_then(|file| std::path::Path::new(file).file_stem())
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}-{stem}", n + 1)
+}
+
+/// Adds the results of files which were run separately to the summary, in order.
+fn merge(summary: Summary, ctx: &mut Context) -> std::io::Result<()> {
+    let mut cases = summary.cases.into_iter().peekable();
+    let mut aborted = summary
+        .failures
+        .into_iter()
+        .filter(|failure| failure.duration.is_none())
+        .peekable();
+
+    for (file_name, duration) in summary.files {
+        while let Some(case) = cases.next_if(|case| case.file_name == file_name) {
+            if ctx.tap {
+                report::tap(&mut std::io::stdout(), ctx.summary.reported() + 1, &case)?;
+            }
+            ctx.summary.record(case);
+        }
+        if let Some(failure) = aborted.next_if(|failure| failure.file_name == file_name) {
+            if ctx.tap {
+                report::tap_abort(&mut std::io::stdout(), ctx.summary.reported() + 1, &failure)?;
+            }
+            ctx.summary.failures.push(failure);
+        }
+        ctx.summary.files.push((file_name, duration));
+    }
+    ctx.summary.files_created += summary.files_created;
+
+    Ok(())
+}
+
+/// How running a file ended.
+enum Ran {
+    Passed,
+    /// A code block failed and the run is not meant to keep going. The failure is already recorded.
+    Failed,
+    /// An error aborted the file.
+    Aborted(Abort),
+}
+
+/// Runs every code block in a file, stopping early if an error aborts it or if a code block fails
+/// and the run is not meant to keep going.
+fn run_file(file_name: &str, options: &Options, ctx: &mut Context) -> std::io::Result<Ran> {
+    ctx.summary.line_number = 0;
+
+    let path = std::path::PathBuf::from(file_name);
+    if !files::is_markdown(&path) {
+        return err_file_ext(file_name).map(Ran::Aborted);
+    }
+
+    let Ok(content) = std::fs::read_to_string(&path) else {
+        return err_file_open(file_name).map(Ran::Aborted);
+    };
+
+    let mut events = match markdown::parse(&content) {
+        Ok(events) => events.into_iter().peekable(),
+        Err(Unclosed::Block { line_number, fence }) => {
+            ctx.summary.line_number = line_number;
+            return err_block_close(file_name, line_number, &fence.to_string()).map(Ran::Aborted);
+        }
+        Err(Unclosed::Comment { line_number }) => {
+            ctx.summary.line_number = line_number;
+            return err_comment_close(file_name, line_number).map(Ran::Aborted);
+        }
+    };
+
+    let mut cmd_ignore = false;
+    let mut cmd_file = None;
+    let mut cmd_expect = None;
+    let mut cmd_code = None;
+    let mut cmd_error = None;
+    let mut cmd_timeout = None;
+    let mut cmd_pty = false;
+    let mut cmd_background = None;
+    let mut cmd_input = String::new();
+    let mut cmd_prompts = Vec::new();
+    let mut cmd_check = rust::Check::Run;
+    let mut cmd_deps = Vec::new();
+    let mut cmd_session = None;
+
+    // Commands running past the end of the file timeout are killed. Timeouts too long to be
+    // represented never run out.
+    let file_deadline = options
+        .file_timeout
+        .and_then(|timeout| std::time::Instant::now().checked_add(timeout));
+
+    // list of variables to be captures from the next code block output
+    let mut var_local = Vec::with_capacity(8);
+    let mut kill_local = Vec::with_capacity(8);
+
+    // Directives waiting for a code block to apply to
+    let mut pending = Vec::new();
+
+    while let Some(event) = events.next() {
+        // Directives are declared in comments or in the info string of code blocks
+        let (directives, block) = match event {
+            Event::Comment { line_number, text } => match directive::from_comment(line_number, &text) {
+                Ok(directives) => (directives, None),
+                Err(error) => return err_syntax(file_name, &error).map(Ran::Aborted),
+            },
+            Event::Code(block) => match directive::from_info(block.line_number, &block.info) {
+                Ok((lang, directives)) => (directives, Some((lang, block))),
+                Err(error) => return err_syntax(file_name, &error).map(Ran::Aborted),
+            },
         };
 
-        let mut buff = std::io::BufReader::new(file);
-        let mut line_number = 0;
-        let mut line_number_code;
-        let mut cmd_ignore = false;
-        let mut cmd_file = None;
-
-        // list of variables to be captures from the next code block output
-        let mut var_local = Vec::with_capacity(8);
-        let mut kill_local = Vec::with_capacity(8);
-
-        while read_line_sanitized(&mut buff, &mut line)? != 0 {
-            line_number += 1;
-
-            // We've found a comment!
-            if line.starts_with("<!--") {
-                // ============================================================================== //
-                //                              DIRECTIVE EXTRACTION                              //
-                // ============================================================================== //
-
-                let mut words = line
-                    .trim_start_matches("<!--")
-                    .trim_end_matches("-->\n")
-                    .split_whitespace();
-                match words.next() {
-                    Some("extract") => {
-                        if !list {
-                            let Some(var) = words.next() else {
-                                return err_extract_no_var(&file_name, line_number);
-                            };
-
-                            let mut pat = String::new();
-                            while let Some(word) = words.next() {
-                                if !pat.is_empty() {
-                                    pat.push(' ');
-                                }
-                                pat.push_str(word);
-                            }
+        for directive in directives {
+            // ============================================================================== //
+            //                              DIRECTIVE EXTRACTION                              //
+            // ============================================================================== //
+
+            let line_number = directive.line_number;
+            ctx.summary.line_number = line_number;
+            ctx.events.directive(file_name, &directive)?;
+            if !directive.is_known() {
+                return err_directive_unknown(file_name, line_number, &directive.name, directive.suggest())
+                    .map(Ran::Aborted);
+            } else if directive.is_attached() {
+                pending.push((line_number, directive.name.clone()));
+            }
 
-                            let pat = pat.trim_matches('"');
-                            let Ok(re) = regex::Regex::new(pat) else {
-                                return err_extract_pattern(&file_name, line_number, pat);
-                            };
+            let args = directive.args.iter().map(ToString::to_string).collect::<Vec<_>>();
+            let mut words = args.iter().map(String::as_str);
+            match directive.name.as_str() {
+                "extract" if options.command != Command::List => {
+                    let Some(var) = words.next() else {
+                        return err_extract_no_var(file_name, line_number).map(Ran::Aborted);
+                    };
 
-                            var_local.push((var.to_string(), re));
+                    let mut pat = String::new();
+                    for word in words.by_ref() {
+                        if !pat.is_empty() {
+                            pat.push(' ');
                         }
+                        pat.push_str(word);
                     }
-                    Some("env") => {
-                        if !list {
-                            let Some(mut var) = words.next().map(String::from) else {
-                                return err_env_no_var(&file_name, line_number);
-                            };
-                            let Some(key) = words.next().map(String::from) else {
-                                return err_env_no_var(&file_name, line_number);
-                            };
-                            let Ok(env) = std::env::var(&key) else {
-                                return err_env_not_set(&file_name, line_number, &key);
-                            };
-
-                            // Capture variables must be formatted as `<VAR_NAME>` for insertion
-                            var.insert(0, '<');
-                            var.push('>');
-                            vars.insert(var.to_string(), env);
-                        }
+
+                    let Ok(re) = regex::Regex::new(&pat) else {
+                        return err_extract_pattern(file_name, line_number, &pat).map(Ran::Aborted);
+                    };
+
+                    // Exported scripts cannot capture variables, so they are left to the user
+                    if options.command == Command::Export {
+                        export::extract(&mut std::io::stdout(), file_name, line_number, var, &pat)?;
+                        ctx.vars.insert(format!("<{var}>"), format!("${{{var}}}"));
+                    }
+
+                    var_local.push((var.to_string(), re));
+                }
+                "env" if options.command != Command::List => {
+                    let Some(mut var) = words.next().map(String::from) else {
+                        return err_env_no_var(file_name, line_number).map(Ran::Aborted);
+                    };
+                    let Some(key) = words.next().map(String::from) else {
+                        return err_env_no_var(file_name, line_number).map(Ran::Aborted);
+                    };
+
+                    // The environment is only read when running code blocks
+                    if options.command == Command::Export {
+                        export::variable(&mut std::io::stdout(), file_name, line_number, &var, &key)?;
+                        ctx.vars.insert(format!("<{var}>"), format!("${{{var}}}"));
+                        continue;
+                    } else if options.command == Command::Check {
+                        continue;
+                    }
+
+                    // Variables from the configuration file are set in the shell as well
+                    let env = match options.env.iter().find(|(name, _)| *name == key) {
+                        Some((_, value)) => value.clone(),
+                        None => match std::env::var(&key) {
+                            Ok(env) => env,
+                            Err(_) => return err_env_not_set(file_name, line_number, &key).map(Ran::Aborted),
+                        },
+                    };
+
+                    // Capture variables must be formatted as `<VAR_NAME>` for insertion
+                    var.insert(0, '<');
+                    var.push('>');
+                    ctx.vars.insert(var.to_string(), env);
+                }
+                "alias" if options.command != Command::List => {
+                    let Some(mut var) = words.next().map(String::from) else {
+                        return err_alias_no_var(file_name, line_number).map(Ran::Aborted);
+                    };
+
+                    var.insert(0, '<');
+                    var.push('>');
+
+                    let Some(mut alias) = words.next().map(String::from) else {
+                        return err_alias_no_var(file_name, line_number).map(Ran::Aborted);
+                    };
+
+                    // Captured variables are only known when running code blocks
+                    if options.command == Command::Export {
+                        let var = var.trim_matches(['<', '>']);
+                        export::variable(&mut std::io::stdout(), file_name, line_number, &alias, var)?;
+                        ctx.vars.insert(format!("<{alias}>"), format!("${{{alias}}}"));
+                        continue;
+                    } else if options.command == Command::Check {
+                        continue;
                     }
-                    Some("alias") => {
-                        if !list {
-                            let Some(mut var) = words.next().map(String::from) else {
-                                return err_alias_no_var(&file_name, line_number);
-                            };
-
-                            var.insert(0, '<');
-                            var.push('>');
-
-                            let Some(mut alias) = words.next().map(String::from) else {
-                                return err_alias_no_var(&file_name, line_number);
-                            };
-                            let Some(val) = vars.get(&var).map(String::from) else {
-                                return err_alias_not_captured(&file_name, line_number, &var);
-                            };
-
-                            alias.insert(0, '<');
-                            alias.push('>');
-                            vars.insert(alias, val);
+
+                    let Some(val) = ctx.vars.get(&var).map(String::from) else {
+                        return err_alias_not_captured(file_name, line_number, &var).map(Ran::Aborted);
+                    };
+
+                    alias.insert(0, '<');
+                    alias.push('>');
+                    ctx.vars.insert(alias, val);
+                }
+                "kill" if options.command != Command::List => {
+                    let mut pat = String::new();
+                    for word in words.by_ref() {
+                        if !pat.is_empty() {
+                            pat.push(' ');
                         }
+                        pat.push_str(word);
                     }
-                    Some("kill") => {
-                        if !list {
-                            let mut pat = String::new();
-                            while let Some(word) = words.next() {
-                                if !pat.is_empty() {
-                                    pat.push(' ');
-                                }
-                                pat.push_str(word);
-                            }
 
-                            let pat = pat.trim_matches('"');
-                            let Ok(re) = regex::Regex::new(pat) else {
-                                return err_kill_pattern(&file_name, line_number, pat);
-                            };
+                    let Ok(re) = regex::Regex::new(&pat) else {
+                        return err_kill_pattern(file_name, line_number, &pat).map(Ran::Aborted);
+                    };
 
-                            kill_local.push(re);
+                    kill_local.push(re);
+                }
+                "teardown" => {
+                    let mut cmd = String::new();
+                    for word in words.by_ref() {
+                        if !cmd.is_empty() {
+                            cmd.push(' ');
                         }
+                        cmd.push_str(word);
                     }
-                    Some("teardown") => {
-                        let mut cmd = String::new();
-                        while let Some(word) = words.next() {
-                            if !cmd.is_empty() {
-                                cmd.push(' ');
+
+                    // Background processes are stopped before tearing down
+                    for bg in ctx.background.drain(..) {
+                        stopped(file_name, bg.line_number, &bg.output(), options.debug)?;
+                        bg.stop()?;
+                    }
+
+                    match options.command {
+                        Command::Run => {
+                            teardown(file_name, line_number, &cmd)?;
+                            ctx.events.teardown(file_name, line_number, &cmd)?;
+                            for shell in ctx.shells.values_mut().chain(ctx.shells_pty.values_mut()) {
+                                shell.write(&cmd)?;
                             }
-                            cmd.push_str(word);
                         }
+                        Command::List => teardown(file_name, line_number, &cmd)?,
+                        Command::Export => export::teardown(&mut std::io::stdout(), file_name, line_number, &cmd)?,
+                        _ => {}
+                    }
+                }
+                "file" => {
+                    let Some(file) = words.next().map(String::from) else {
+                        return err_file_name(file_name, line_number).map(Ran::Aborted);
+                    };
+                    cmd_file = Some(file);
+                }
+                "ignore" => {
+                    cmd_ignore = true;
+                }
+                "pty" => {
+                    cmd_pty = true;
+                }
+                "input" if options.command != Command::List => {
+                    let mut input = String::new();
+                    for word in words.by_ref() {
+                        if !input.is_empty() {
+                            input.push(' ');
+                        }
+                        input.push_str(word);
+                    }
 
-                        let cmd = cmd.trim_matches('"');
+                    cmd_input.push_str(&directive::unescape(&input));
+                }
+                "expect-prompt" if options.command != Command::List => {
+                    // Prompts are written as `PATTERN answer ANSWER` or `PATTERN answer=ANSWER`
+                    let words = directive
+                        .args
+                        .iter()
+                        .filter(|arg| arg.key.is_none())
+                        .map(Arg::value)
+                        .collect::<Vec<_>>();
+                    let (pat, answer) = match words.iter().rposition(|word| word == "answer") {
+                        Some(n) => (words[..n].join(" "), Some(words[n + 1..].join(" "))),
+                        None => (words.join(" "), directive.option("answer")),
+                    };
 
-                        teardown(&file_name, line_number, cmd)?;
+                    let Some(mut answer) = answer else {
+                        return err_prompt_no_answer(file_name, line_number).map(Ran::Aborted);
+                    };
+                    let Ok(re) = regex::Regex::new(&pat) else {
+                        return err_prompt_pattern(file_name, line_number, &pat).map(Ran::Aborted);
+                    };
 
-                        if !list {
-                            writeln!(&cmd_stdin, "{cmd}")?;
+                    // Answers are submitted as if typed followed by enter
+                    answer.push('\n');
+
+                    cmd_prompts.push((re, answer));
+                }
+                "background" if options.command != Command::List => {
+                    let mut pat = String::new();
+                    for word in words.by_ref() {
+                        if !pat.is_empty() {
+                            pat.push(' ');
                         }
+                        pat.push_str(word);
                     }
-                    Some("file") => {
-                        let Some(file) = words.next().map(String::from) else {
-                            return err_file_name(&file_name, line_number);
+
+                    if pat.is_empty() {
+                        cmd_background = Some(None);
+                    } else {
+                        let Ok(re) = regex::Regex::new(&pat) else {
+                            return err_background_pattern(file_name, line_number, &pat).map(Ran::Aborted);
                         };
-                        cmd_file = Some(file);
+                        cmd_background = Some(Some(re));
                     }
-                    Some("ignore") => {
-                        cmd_ignore = true;
-                    }
-                    _ => {}
                 }
-            }
-            // We've found a code block!
-            else if line.starts_with("```") {
-                // ============================================================================== //
-                //                               COMMAND EXTRACTION                               //
-                // ============================================================================== //
-                line_number_code = line_number;
-                let lang = line[3..line.len()].trim_end().to_string();
-
-                if lang.len() == 0 {
-                    return err_no_lang(&file_name, line_number);
+                "expect" => {
+                    let stream = match words.next() {
+                        None | Some("stdout") => Stream::Stdout,
+                        Some("stderr") => Stream::Stderr,
+                        Some("both") => Stream::Both,
+                        Some(stream) => return err_expect_stream(file_name, line_number, stream).map(Ran::Aborted),
+                    };
+                    cmd_expect = Some(stream);
                 }
+                "exit-code" if options.command != Command::List => {
+                    cmd_code = match words.next() {
+                        Some("nonzero") => Some(Code::NonZero),
+                        Some(code) => match code.parse::<i32>() {
+                            Ok(n) => Some(Code::Exact(n)),
+                            Err(_) => return err_exit_code(file_name, line_number, code).map(Ran::Aborted),
+                        },
+                        None => return err_exit_code(file_name, line_number, "").map(Ran::Aborted),
+                    };
+                }
+                "expect-error" if options.command != Command::List => {
+                    let mut pat = String::new();
+                    for word in words.by_ref() {
+                        if !pat.is_empty() {
+                            pat.push(' ');
+                        }
+                        pat.push_str(word);
+                    }
 
-                line.clear();
+                    let Ok(re) = regex::Regex::new(&pat) else {
+                        return err_expect_error_pattern(file_name, line_number, &pat).map(Ran::Aborted);
+                    };
 
-                while read_line_sanitized_cmd(&mut buff, &mut line)? != 0 && !line.trim().starts_with("```") {
-                    line_number_code += 1;
-                    cmd.push_str(&line);
-                    line.clear();
+                    cmd_error = Some(re);
+                }
+                "session" => {
+                    let Some(session) = words.next() else {
+                        return err_session_no_name(file_name, line_number).map(Ran::Aborted);
+                    };
+                    cmd_session = Some(session.to_string());
                 }
+                "no_run" => cmd_check = rust::Check::NoRun,
+                "should_panic" => cmd_check = rust::Check::ShouldPanic,
+                "compile_fail" => cmd_check = rust::Check::CompileFail,
+                "deps" if options.command != Command::List => {
+                    for arg in directive.args.iter() {
+                        let Some(name) = arg.key.clone() else {
+                            return err_deps(file_name, line_number, &arg.to_string()).map(Ran::Aborted);
+                        };
 
-                if line.trim() != "```" {
-                    return err_block_close(&file_name, line_number, &line);
+                        // Versions have to be vendored since builds are offline
+                        let value = arg.value();
+                        if value.starts_with(|c: char| c.is_ascii_digit() || "^~=<>*".contains(c)) {
+                            cmd_deps.push(rust::Dep::Version { name, version: value });
+                        } else {
+                            let path = std::path::absolute(path.with_file_name(value))?;
+                            cmd_deps.push(rust::Dep::Path { name, path });
+                        }
+                    }
                 }
+                "timeout" if options.command != Command::List => {
+                    let duration = words.next().unwrap_or_default();
+                    let Some(duration) = cli::parse_duration(duration) else {
+                        return err_timeout(file_name, line_number, duration).map(Ran::Aborted);
+                    };
+                    cmd_timeout = Some(duration);
+                }
+                // Directives which have no effect when listing code blocks
+                _ => {}
+            }
+        }
+        // We've found a code block!
+        if let Some((lang, block)) = block {
+            // ============================================================================== //
+            //                               COMMAND EXTRACTION                               //
+            // ============================================================================== //
+            let line_number = block.line_number;
+            ctx.summary.line_number = line_number;
+            pending.clear();
+            let fence = block.fence.to_string();
+            let check = std::mem::replace(&mut cmd_check, rust::Check::Run);
+            let deps = std::mem::take(&mut cmd_deps);
+            let session = cmd_session.take();
+
+            if lang.is_empty() {
+                return err_no_lang(file_name, line_number).map(Ran::Aborted);
+            }
 
-                // Creates commands and interpolates any known capture variables
-                let mut program_and_args = cmd.to_string();
-                for (var, val) in vars.iter() {
-                    program_and_args = program_and_args.replace(var, val.as_ref());
+            // The expected output of a command is read from the next code block
+            let mut expected = None;
+            if let Some(stream) = cmd_expect.take() {
+                // Regular comments can come in between, but directives are never skipped over
+                while let Some(Event::Comment { line_number, text }) = events.peek()
+                    && directive::from_comment(*line_number, text).is_ok_and(|directives| directives.is_empty())
+                {
+                    events.next();
                 }
+                let Some(Event::Code(expect_out)) = events.next_if(|event| matches!(event, Event::Code(_))) else {
+                    return err_expect_no_block(file_name, line_number).map(Ran::Aborted);
+                };
 
-                if let Some(file) = cmd_file {
-                    let path = path.with_file_name(file);
-                    let path_str = path.to_string_lossy();
+                expected = Some((stream, expect_out.code));
+            }
 
-                    draw_file_info(&mut out, Status::NEWFILE, &path_str, line_number)?;
-                    draw_code(&mut out, Status::NEWFILE, &lang, &program_and_args, true)?;
-                    flush(&mut out)?;
+            // An expected error implies a failing command unless told otherwise
+            let expect_error = cmd_error.take();
+            let expect_code = match cmd_code.take() {
+                Some(code) => code,
+                None if expect_error.is_some() => Code::NonZero,
+                None => Code::Exact(0),
+            };
+
+            let deadline = cmd_timeout
+                .take()
+                .or(options.timeout)
+                .and_then(|timeout| std::time::Instant::now().checked_add(timeout))
+                .into_iter()
+                .chain(file_deadline)
+                .min();
+
+            // Creates commands and interpolates any known capture variables
+            let mut program_and_args = block.code;
+            for (var, val) in ctx.vars.iter() {
+                program_and_args = program_and_args.replace(var, val.as_ref());
+            }
 
-                    if !list {
-                        let mut file = std::fs::File::create(path)?;
-                        file.write_all(program_and_args.as_bytes())?;
-                    }
+            if let Some(file) = cmd_file {
+                // Files are created next to the Markdown file, or in the sandbox when running in one
+                let path = match ctx.workspace.as_ref() {
+                    Some(dir) => dir.join(file),
+                    None => path.with_file_name(file),
+                };
+                let path_str = path.to_string_lossy();
+
+                if let Command::Run | Command::List = options.command {
+                    draw_file_info(&mut ctx.out, Status::NEWFILE, &path_str, line_number, None)?;
+                    draw_code(&mut ctx.out, Status::NEWFILE, &fence, &lang, &program_and_args, true)?;
+                    flush(&mut ctx.out)?;
+                }
 
-                    cmd_file = None;
-
-                    line.clear();
-                    cmd.clear();
-                    line_number = line_number_code + 1;
-                    continue;
-                } else if lang != "bash" && lang != "sh" || cmd_ignore {
-                    ignored(&file_name, line_number, &program_and_args, debug)?;
-
-                    cmd_ignore = false;
-
-                    line.clear();
-                    cmd.clear();
-                    line_number = line_number_code + 1;
-                    continue;
-                } else if list {
-                    listed(&file_name, line_number, &program_and_args, debug)?;
-                    line.clear();
-                    cmd.clear();
-                    line_number = line_number_code + 1;
-                    continue;
-                }
-
-                // Commands are run in the specified shell.
-                // Currently, only `bash` and `sh` are supported.
-                writeln!(
-                    &cmd_stdin,
-                    "{}; echo \"$?:CMDEND\"; echo :CMDEND 1>&2",
-                    program_and_args.trim_end()
-                )?;
+                if options.command == Command::Run {
+                    let mut file = std::fs::File::create(&path)?;
+                    file.write_all(program_and_args.as_bytes())?;
+                    ctx.summary.files_created += 1;
+                    ctx.events.file_created(file_name, line_number, &path_str)?;
+                } else if options.command == Command::Export {
+                    let mut stdout = std::io::stdout();
+                    export::file(&mut stdout, file_name, line_number, &path_str, &program_and_args)?;
+                }
 
-                // ============================================================================== //
-                //                                  DRAW ROUTINE                                  //
-                // ============================================================================== //
+                cmd_file = None;
+
+                continue;
+            } else if options.ignore_languages.contains(&lang) {
+                // Code blocks such as sample output are left out of the run entirely, along with the
+                // directives which apply to them
+                cmd_ignore = false;
+                cmd_background = None;
+                cmd_pty = false;
+                cmd_input.clear();
+                cmd_prompts.clear();
+                continue;
+            } else if runner::find(&options.runners, &lang).is_none() || cmd_ignore {
+                if let Command::Run | Command::List = options.command {
+                    ignored(file_name, line_number, &program_and_args, options.debug)?;
+                }
+                ctx.events
+                    .block_start(file_name, line_number, &lang, session.as_deref(), &program_and_args)?;
+                record(
+                    Case {
+                        file_name: file_name.to_string(),
+                        line_number,
+                        duration: std::time::Duration::ZERO,
+                        script: program_and_args,
+                        exit_code: None,
+                        stdout: String::new(),
+                        stderr: String::new(),
+                        outcome: Outcome::Ignored,
+                    },
+                    ctx,
+                )?;
 
-                let mut line_count = 0;
+                // Directives which only apply when running the code block
+                cmd_ignore = false;
+                cmd_background = None;
+                cmd_pty = false;
+                cmd_input.clear();
+                cmd_prompts.clear();
+
+                continue;
+            } else if options.command == Command::List {
+                listed(file_name, line_number, &program_and_args, options.debug)?;
+                continue;
+            } else if options.command != Command::Run {
+                let background = cmd_background.take().is_some();
+                if options.command == Command::Export {
+                    let mut stdout = std::io::stdout();
+                    let runner = runner::find(&options.runners, &lang).expect("Checked above");
+                    match runner.kind {
+                        Kind::Session => {
+                            export::block(&mut stdout, file_name, line_number, &program_and_args, None, background)?
+                        }
+                        Kind::Script => export::block(
+                            &mut stdout,
+                            file_name,
+                            line_number,
+                            &program_and_args,
+                            Some(&runner.command),
+                            background,
+                        )?,
+                        Kind::Cargo => {
+                            let name = rust::name();
+                            export::rust(
+                                &mut stdout,
+                                file_name,
+                                line_number,
+                                &runner.command,
+                                &name,
+                                &rust::manifest(&name, &deps),
+                                &rust::source(&program_and_args),
+                                check,
+                                background,
+                            )?
+                        }
+                    }
+                }
 
-                let mut stdout = String::with_capacity(256);
-                let mut stderr = String::with_capacity(256);
-                let code;
+                // Directives which only apply when running the code block
+                cmd_pty = false;
+                cmd_input.clear();
+                cmd_prompts.clear();
+                var_local.clear();
+                continue;
+            }
 
-                write!(out, "{WRAP_DISABLE}")?;
+            // Commands are run in the session of the shell for their language, so that bashisms are
+            // not run by `sh`. Other languages are written to a script or cargo project which is run
+            // from `sh`, so that exit codes, timeouts and input work the same way.
+            let Some(runner) = runner::find(&options.runners, &lang) else {
+                unreachable!("Code blocks in other languages are ignored");
+            };
+            let (program, code) = match runner.kind {
+                Kind::Session => (runner.command.clone(), program_and_args.clone()),
+                Kind::Script => {
+                    let extension = lang.replace(|c: char| !c.is_ascii_alphanumeric(), "");
+                    let script = Script::new(&program_and_args, &extension)?;
+                    let code = script.command(&runner.command);
+                    ctx.scripts.push(script);
+                    ("sh".to_string(), code)
+                }
+                Kind::Cargo => {
+                    let project = rust::Project::new(&program_and_args, &deps)?;
+                    if ctx.target.is_none() {
+                        ctx.target = Some(rust::Target::new()?);
+                    }
+                    let target = ctx.target.as_ref().expect("Created above");
+                    let code = project.command(&runner.command, target, check);
+                    ctx.projects.push(project);
+                    ("sh".to_string(), code)
+                }
+            };
+            let (shells, mode) = if std::mem::take(&mut cmd_pty) && options.mode == Mode::Pipe {
+                (&mut ctx.shells_pty, Mode::Pty)
+            } else {
+                (&mut ctx.shells, options.mode)
+            };
+            let shell = match shells.entry((session.clone(), program.clone())) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    match Shell::spawn(mode, &program, &options.env, ctx.workspace.as_deref()) {
+                        Ok(shell) => entry.insert(shell),
+                        Err(_) => return err_shell_spawn(file_name, line_number, &program).map(Ran::Aborted),
+                    }
+                }
+            };
+            let mode = shell.mode();
+
+            // Code blocks with scripted input read it from a dedicated channel
+            let prompts = std::mem::take(&mut cmd_prompts);
+            let input_upfront = std::mem::take(&mut cmd_input);
+            let mut input = if !prompts.is_empty() || !input_upfront.is_empty() {
+                Some(shell.input()?)
+            } else {
+                None
+            };
+            let script = match input.as_ref() {
+                Some(input) => input.redirect(&code),
+                None => code,
+            };
+
+            // Background code blocks are wrapped so that they return as soon as they are started
+            let start = std::time::Instant::now();
+            ctx.events
+                .block_start(file_name, line_number, &lang, session.as_deref(), &program_and_args)?;
+            let bg = match cmd_background.take() {
+                Some(ready) => Some((ready, Background::new(line_number)?)),
+                None => None,
+            };
+            match bg.as_ref() {
+                Some((_, bg)) => shell.run(&bg.script(&script))?,
+                None => shell.run(&script)?,
+            }
 
-                line_count += draw_file_info(&mut out, Status::RUNNING, &file_name, line_number)?;
-                line_count += draw_code(&mut out, Status::RUNNING, &lang, &program_and_args, false)?;
-                line_count += draw_output(&mut out, Status::RUNNING, &stdout, "stdout", true)?;
-                flush(&mut out)?;
+            if let Some(input) = input.as_mut() {
+                input.write(&input_upfront);
+                if prompts.is_empty() {
+                    input.close();
+                }
+            }
 
-                while !stdout.ends_with(":CMDEND\n") && shell.try_wait()?.is_none() {
-                    cmd_stdout.read_line(&mut stdout)?;
+            // ============================================================================== //
+            //                                  DRAW ROUTINE                                  //
+            // ============================================================================== //
+
+            let mut line_count = 0;
+
+            let mut stdout = String::with_capacity(256);
+            let mut stderr = String::with_capacity(256);
+            let mut exited = false;
+            let mut killed = false;
+            let mut timed_out = false;
+
+            // Prompts are answered in order, each one being matched against the output which
+            // came after the previous prompt
+            let mut prompt = 0;
+            let mut prompt_stdout = 0;
+            let mut prompt_stderr = 0;
+
+            // Output which has already been sent as events
+            let mut sent_stdout = 0;
+            let mut sent_stderr = 0;
+
+            // Code blocks are only redrawn as they run in a terminal, otherwise they are drawn once
+            // they are done
+            if options.interactive {
+                write!(ctx.out, "{WRAP_DISABLE}")?;
+
+                line_count += draw_file_info(
+                    &mut ctx.out,
+                    Status::RUNNING,
+                    file_name,
+                    line_number,
+                    session.as_deref(),
+                )?;
+                line_count += draw_code(&mut ctx.out, Status::RUNNING, &fence, &lang, &program_and_args, false)?;
+                line_count += draw_output(&mut ctx.out, Status::RUNNING, &stdout, "stdout", true)?;
+                flush(&mut ctx.out)?;
+            }
 
-                    for re in kill_local.iter() {
-                        if re.is_match(&stdout) {
-                            shell.kill()?;
-                            shell.wait()?;
-                            break;
-                        }
+            while !shell.is_done(&stdout, &stderr) {
+                match shell.recv(deadline) {
+                    Ok(Chunk::Stdout(chunk)) => stdout.push_str(&chunk),
+                    Ok(Chunk::Stderr(chunk)) => stderr.push_str(&chunk),
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                        timed_out = true;
+                        break;
                     }
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                        exited = true;
+                        break;
+                    }
+                }
 
-                    erase(&mut out, line_count)?;
-                    line_count = draw_file_info(&mut out, Status::RUNNING, &file_name, line_number)?;
-                    line_count += draw_code(&mut out, Status::RUNNING, &lang, &program_and_args, false)?;
-                    line_count += draw_output(&mut out, Status::RUNNING, &stdout, "stdout", true)?;
-                    flush(&mut out)?;
+                // The output of background code blocks is only known once they are ready
+                if bg.is_none() {
+                    let visible = shell.visible(&stdout).max(sent_stdout);
+                    ctx.events
+                        .output(file_name, line_number, "stdout", &stdout[sent_stdout..visible])?;
+                    sent_stdout = visible;
+
+                    let visible = shell.visible(&stderr).max(sent_stderr);
+                    ctx.events
+                        .output(file_name, line_number, "stderr", &stderr[sent_stderr..visible])?;
+                    sent_stderr = visible;
                 }
 
-                while !stderr.ends_with(":CMDEND\n") && shell.try_wait()?.is_none() {
-                    cmd_stderr.read_line(&mut stderr)?;
+                if kill_local.iter().any(|re| re.is_match(&stdout) || re.is_match(&stderr)) {
+                    killed = true;
+                    break;
+                }
 
-                    for re in kill_local.iter() {
-                        if re.is_match(&stderr) {
-                            shell.kill()?;
-                            shell.wait()?;
-                            break;
-                        }
+                if let Some(input) = input.as_mut()
+                    && let Some((re, answer)) = prompts.get(prompt)
+                    && (re.is_match(&stdout[prompt_stdout..]) || re.is_match(&stderr[prompt_stderr..]))
+                {
+                    input.write(answer);
+                    prompt += 1;
+                    prompt_stdout = stdout.len();
+                    prompt_stderr = stderr.len();
+
+                    if prompt == prompts.len() {
+                        input.close();
                     }
+                }
 
-                    erase(&mut out, line_count)?;
-                    line_count = draw_file_info(&mut out, Status::RUNNING, &file_name, line_number)?;
-                    line_count += draw_code(&mut out, Status::RUNNING, &lang, &program_and_args, false)?;
-                    line_count += draw_output(&mut out, Status::RUNNING, &stdout, "stdout", false)?;
-                    line_count += draw_output(&mut out, Status::RUNNING, &stderr, "stdout", true)?;
-                    flush(&mut out)?;
-                }
-
-                match shell.try_wait()? {
-                    Some(code_raw) => {
-                        code = match code_raw.code() {
-                            Some(n) => n,
-                            None => {
-                                shell = std::process::Command::new("sh")
-                                    .stdin(std::process::Stdio::piped())
-                                    .stdout(std::process::Stdio::piped())
-                                    .stderr(std::process::Stdio::piped())
-                                    .spawn()?;
-                                cmd_stdin = shell.stdin.take().unwrap();
-                                cmd_stdout = std::io::BufReader::new(shell.stdout.take().unwrap());
-                                cmd_stderr = std::io::BufReader::new(shell.stderr.take().unwrap());
-                                0
-                            }
-                        };
+                if options.interactive {
+                    erase(&mut ctx.out, line_count)?;
+                    line_count = draw_file_info(
+                        &mut ctx.out,
+                        Status::RUNNING,
+                        file_name,
+                        line_number,
+                        session.as_deref(),
+                    )?;
+                    line_count += draw_code(&mut ctx.out, Status::RUNNING, &fence, &lang, &program_and_args, false)?;
+                    line_count += draw_output(&mut ctx.out, Status::RUNNING, &stdout, "stdout", stderr.is_empty())?;
+                    if !stderr.is_empty() {
+                        line_count += draw_output(&mut ctx.out, Status::RUNNING, &stderr, "stderr", true)?;
                     }
-                    None => {
-                        let re = regex::Regex::new(r#"(\d+):CMDEND"#).unwrap();
-                        let code_raw = re.captures(&stdout).unwrap().get(1).unwrap().as_str();
-
-                        code = code_raw.parse::<i32>().unwrap();
+                    flush(&mut ctx.out)?;
+                }
+            }
 
-                        stdout = stdout
-                            .trim_end_matches(":CMDEND\n")
-                            .trim_end_matches(code_raw)
-                            .to_string();
+            drop(input);
+
+            // Killing a command or exiting the shell loses its execution context, so a new shell
+            // has to be spawned
+            let mut code = if killed || timed_out {
+                shell.kill()?;
+                *shell = Shell::spawn(mode, &program, &options.env, ctx.workspace.as_deref())?;
+                0
+            } else if exited {
+                let code = shell.wait()?.code().unwrap_or_default();
+                *shell = Shell::spawn(mode, &program, &options.env, ctx.workspace.as_deref())?;
+                code
+            } else {
+                shell.strip_markers(&mut stdout, &mut stderr)
+            };
+
+            // ============================================================================== //
+            //                              BACKGROUND READINESS                              //
+            // ============================================================================== //
+
+            // Background code blocks are ready once their output matches the readiness pattern,
+            // if any. They fail if they terminate before that.
+            let mut bg_ready = false;
+            if let Some((ready, mut bg)) = bg
+                && !killed
+                && !timed_out
+                && code == 0
+            {
+                bg.pid = stdout.trim().parse().unwrap_or_default();
+                loop {
+                    stdout = bg.output();
+
+                    if ready.as_ref().is_none_or(|re| re.is_match(&stdout)) {
+                        bg_ready = true;
+                        break;
+                    } else if let Some(bg_code) = bg.exit_code() {
+                        code = if bg_code != 0 { bg_code } else { 1 };
+                        break;
+                    } else if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+                        timed_out = true;
+                        bg.stop()?;
+                        break;
+                    }
 
-                        stderr = stderr.trim_end_matches(":CMDEND\n").to_string();
+                    if options.interactive {
+                        erase(&mut ctx.out, line_count)?;
+                        line_count = draw_file_info(
+                            &mut ctx.out,
+                            Status::RUNNING,
+                            file_name,
+                            line_number,
+                            session.as_deref(),
+                        )?;
+                        line_count +=
+                            draw_code(&mut ctx.out, Status::RUNNING, &fence, &lang, &program_and_args, false)?;
+                        line_count += draw_output(&mut ctx.out, Status::RUNNING, &stdout, "stdout", true)?;
+                        flush(&mut ctx.out)?;
                     }
+
+                    std::thread::sleep(std::time::Duration::from_millis(50));
                 }
 
-                let status = if code == 0 { Status::PASS } else { Status::FAIL };
+                if bg_ready {
+                    ctx.background.push(bg);
+                }
+            }
 
-                erase(&mut out, line_count)?;
-                if !debug {
-                    draw_file_info(&mut out, status, &file_name, line_number)?;
-                    draw_code(&mut out, status, &lang, &program_and_args, true)?;
-                } else {
-                    draw_file_info(&mut out, status, &file_name, line_number)?;
-                    draw_code(&mut out, status, &lang, &program_and_args, false)?;
-                    draw_output(&mut out, status, &stdout, "sdtout", false)?;
-                    draw_output(&mut out, status, &stderr, "sdterr", true)?;
+            // Output which was held back while looking for end markers
+            let rest = stdout.get(sent_stdout..).unwrap_or_default();
+            ctx.events.output(file_name, line_number, "stdout", rest)?;
+            let rest = stderr.get(sent_stderr..).unwrap_or_default();
+            ctx.events.output(file_name, line_number, "stderr", rest)?;
+
+            // Compares the output of the command to its expected output, if any
+            let actual = expected
+                .as_ref()
+                .map(|(stream, _)| stream.select(&stdout, &stderr))
+                .unwrap_or_default();
+            // Code blocks which timed out have no exit code and partial output, so only the timeout is
+            // reported
+            let diff = expected
+                .as_ref()
+                .filter(|_| !timed_out)
+                .and_then(|(_, expected)| expect::diff(expected, &actual));
+
+            let code_fail = !timed_out && !expect_code.matches(code);
+            let error_fail = !timed_out && expect_error.as_ref().is_some_and(|re| !re.is_match(&stderr));
+
+            let status = if timed_out {
+                Status::TIMEOUT
+            } else if !code_fail && !error_fail && diff.is_none() && bg_ready {
+                Status::BACKGROUND
+            } else if !code_fail && !error_fail && diff.is_none() {
+                Status::PASS
+            } else {
+                Status::FAIL
+            };
+
+            if options.interactive {
+                erase(&mut ctx.out, line_count)?;
+            }
+            if timed_out {
+                // Partial output is always shown to help figure out where the command hung
+                draw_file_info(&mut ctx.out, status, file_name, line_number, session.as_deref())?;
+                draw_code(&mut ctx.out, status, &fence, &lang, &program_and_args, false)?;
+                draw_output(&mut ctx.out, status, &stdout, "stdout", false)?;
+                draw_output(&mut ctx.out, status, &stderr, "stderr", true)?;
+            } else if !options.debug {
+                let terminate = !error_fail && !code_fail && diff.is_none();
+                draw_file_info(&mut ctx.out, status, file_name, line_number, session.as_deref())?;
+                draw_code(&mut ctx.out, status, &fence, &lang, &program_and_args, terminate)?;
+                if error_fail {
+                    draw_output(&mut ctx.out, status, &stderr, "stderr", !code_fail && diff.is_none())?;
                 }
+            } else {
+                draw_file_info(&mut ctx.out, status, file_name, line_number, session.as_deref())?;
+                draw_code(&mut ctx.out, status, &fence, &lang, &program_and_args, false)?;
+                draw_output(&mut ctx.out, status, &stdout, "sdtout", false)?;
+                draw_output(&mut ctx.out, status, &stderr, "sdterr", !code_fail && diff.is_none())?;
+            }
 
-                write!(out, "{WRAP_ENABLE}")?;
-                flush(&mut out)?;
+            if code_fail {
+                draw_exit_code(&mut ctx.out, status, code, expect_code, diff.is_none())?;
+            }
 
-                if code != 0 {
-                    return Ok(std::process::ExitCode::FAILURE);
-                }
+            if let Some((stream, _)) = &expected
+                && let Some(diff) = &diff
+            {
+                draw_diff(&mut ctx.out, status, diff, stream.name())?;
+            }
 
-                // ============================================================================== //
-                //                                 OUTPUT CAPTURE                                 //
-                // ============================================================================== //
+            if options.interactive {
+                write!(ctx.out, "{WRAP_ENABLE}")?;
+            }
+            flush(&mut ctx.out)?;
 
-                // Looks for capture variables in the output of the command.
-                // By default we look for captures in `stdout`. If none are found we look in
-                // `stderr`. If no capture is found this counts as an error.
-                for (mut var, re) in var_local {
-                    let Some(cap) = re
-                        .captures(&stdout)
-                        .or_else(|| re.captures(&stderr))
-                        .and_then(|cap| cap.get(1))
-                        .map(|cap| cap.as_str().to_string())
-                    else {
-                        return err_cmd_capture(&file_name, line_number, &program_and_args, &stdout, &stderr, &re);
-                    };
+            // Failing code blocks do not capture any variables
+            if let Status::FAIL | Status::TIMEOUT = status {
+                let mut reasons = Vec::new();
+                if timed_out {
+                    reasons.push("Timed out".to_string());
+                }
+                if code_fail {
+                    reasons.push(format!("exit code {code} (expected {expect_code})"));
+                }
+                if error_fail && let Some(re) = &expect_error {
+                    reasons.push(format!("stderr does not match \"{re}\""));
+                }
 
-                    // Capture variables must be formatted as `<VAR_NAME>` for insertion
-                    var.insert(0, '<');
-                    var.push('>');
+                // The diff is kept below the reasons, which are all on the first line
+                let mut message = reasons.join(", ");
+                if let Some((stream, _)) = &expected
+                    && let Some(diff) = &diff
+                {
+                    if !message.is_empty() {
+                        message.push_str(", ");
+                    }
+                    message.push_str(&format!("{} does not match (-expected, +actual)", stream.name()));
+                    for line in diff {
+                        match line {
+                            Diff::Same(line) => message.push_str(&format!("\n {line}")),
+                            Diff::Expected(line) => message.push_str(&format!("\n-{line}")),
+                            Diff::Actual(line) => message.push_str(&format!("\n+{line}")),
+                        }
+                    }
+                }
 
-                    vars.insert(var, cap);
+                let case = Case {
+                    file_name: file_name.to_string(),
+                    line_number,
+                    duration: start.elapsed(),
+                    script: program_and_args,
+                    exit_code: (!timed_out && !killed).then_some(code),
+                    stdout,
+                    stderr,
+                    outcome: Outcome::Failed(message),
+                };
+                record(case, ctx)?;
+                if options.fail_fast != FailFast::Never {
+                    return Ok(Ran::Failed);
                 }
                 var_local = Vec::with_capacity(8);
-
-                cmd.clear();
-                line_number = line_number_code + 1;
+                continue;
             }
 
-            line.clear();
+            // ============================================================================== //
+            //                                 OUTPUT CAPTURE                                 //
+            // ============================================================================== //
+
+            // Looks for capture variables in the output of the command.
+            // By default we look for captures in `stdout`. If none are found we look in
+            // `stderr`. If no capture is found this counts as an error.
+            let mut missing = None;
+            for (mut var, re) in var_local {
+                let Some(cap) = re
+                    .captures(&stdout)
+                    .or_else(|| re.captures(&stderr))
+                    .and_then(|cap| cap.get(1))
+                    .map(|cap| cap.as_str().to_string())
+                else {
+                    err_cmd_capture(file_name, line_number, &program_and_args, &stdout, &stderr, &re)?;
+                    missing = Some(re);
+                    break;
+                };
+
+                ctx.events.variable(file_name, line_number, &var, &cap)?;
+
+                // Capture variables must be formatted as `<VAR_NAME>` for insertion
+                var.insert(0, '<');
+                var.push('>');
+
+                ctx.vars.insert(var, cap);
+            }
+            var_local = Vec::with_capacity(8);
+
+            let outcome = match &missing {
+                Some(re) => Outcome::Failed(format!("Failed to capture matches: \"{re}\"")),
+                None => Outcome::Passed,
+            };
+            let case = Case {
+                file_name: file_name.to_string(),
+                line_number,
+                duration: start.elapsed(),
+                script: program_and_args,
+                exit_code: Some(code),
+                stdout,
+                stderr,
+                outcome,
+            };
+            record(case, ctx)?;
+
+            if missing.is_some() && options.fail_fast != FailFast::Never {
+                return Ok(Ran::Failed);
+            }
         }
     }
 
-    shell.kill()?;
+    // Directives which are not followed by a code block are most likely a mistake
+    if let Some((line_number, name)) = pending.first() {
+        ctx.summary.line_number = *line_number;
+        return err_directive_dangling(file_name, *line_number, name).map(Ran::Aborted);
+    }
 
-    Ok(std::process::ExitCode::SUCCESS)
-}
+    if options.command == Command::Check {
+        checked(file_name)?;
+    }
 
-fn read_line_sanitized(buff: &mut impl std::io::BufRead, line: &mut String) -> std::io::Result<usize> {
-    let n = buff.read_line(line)?;
-    *line = line.strip_prefix('>').unwrap_or(&line).trim_start().to_string();
-    Ok(n)
+    Ok(Ran::Passed)
 }
 
-fn read_line_sanitized_cmd(buff: &mut impl std::io::BufRead, line: &mut String) -> std::io::Result<usize> {
-    let n = buff.read_line(line)?;
-    *line = line.strip_prefix('>').unwrap_or(&line).to_string();
-    Ok(n)
+/// Records the result of a code block, reporting it straight away when writing TAP or events.
+fn record(case: Case, ctx: &mut Context) -> std::io::Result<()> {
+    if ctx.tap {
+        report::tap(&mut std::io::stdout(), ctx.summary.reported() + 1, &case)?;
+    }
+    ctx.events.block_end(&case)?;
+    ctx.summary.record(case);
+    Ok(())
 }
@@ -0,0 +1,437 @@
+//! Minimal markdown parser, which only extracts comments and fenced code blocks.
+//!
+//! Comments are either html comments, which can span multiple lines, or link reference definitions
+//! such as `[//]: # (comment)`, which are commonly used as comments since they are not rendered.
+//!
+//! Fenced code blocks follow the [CommonMark] rules: fences are made of at least three backticks
+//! or tildes, can be indented by up to three spaces and are closed by a fence made of the same
+//! character which is at least as long. Fences can be nested inside of blockquotes and list items,
+//! in which case they are also closed at the end of their container.
+//!
+//! [CommonMark]: https://spec.commonmark.org/0.31.2/#fenced-code-blocks
+
+/// Element of a markdown file which `md-command-check` cares about.
+pub enum Event {
+    /// Contents of a comment, which may hold directives.
+    Comment { line_number: usize, text: String },
+    /// Fenced code block.
+    Code(Block),
+}
+
+pub struct Block {
+    /// Line of the opening fence.
+    pub line_number: usize,
+    pub fence: Fence,
+    /// Everything following the opening fence, such as the language of the code block.
+    pub info: String,
+    /// Contents of the code block, stripped of any container and fence indentation.
+    pub code: String,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fence {
+    pub char: char,
+    pub len: usize,
+    /// Indentation of the opening fence, relative to its container.
+    pub indent: usize,
+    pub container: Container,
+}
+
+/// Blockquotes and list items a code block is nested in.
+#[derive(Clone, Copy, Default)]
+pub struct Container {
+    /// Number of `>` blockquote markers.
+    pub quotes: usize,
+    /// Column at which the content of the innermost list item starts.
+    pub indent: usize,
+}
+
+impl std::fmt::Display for Fence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for _ in 0..self.len {
+            write!(f, "{}", self.char)?;
+        }
+        Ok(())
+    }
+}
+
+/// A code block or an html comment is missing its closing delimiter.
+pub enum Unclosed {
+    Block { line_number: usize, fence: Fence },
+    Comment { line_number: usize },
+}
+
+/// Parses a markdown file into a list of comments and code blocks, in order of appearance.
+pub fn parse(content: &str) -> Result<Vec<Event>, Unclosed> {
+    let mut events = Vec::new();
+    let mut block = None::<Block>;
+    let mut comment = None::<(usize, usize, String)>;
+
+    // Content columns of the currently open list items, from outermost to innermost
+    let mut lists = Vec::<usize>::new();
+    let mut quotes_prev = 0;
+
+    // `lines` takes care of stripping `\r\n` line endings
+    for (n, line) in content.lines().enumerate() {
+        let line_number = n + 1;
+
+        // Html comments run until the first `-->`, everything else is ignored
+        if let Some((_, quotes, text)) = comment.as_mut() {
+            let (_, rest) = strip_quotes(line, *quotes);
+            match rest.find("-->") {
+                Some(end) => {
+                    text.push_str(&rest[..end]);
+                    let (line_number, _, text) = comment.take().expect("Checked above");
+                    events.push(Event::Comment { line_number, text });
+                }
+                None => {
+                    text.push_str(rest);
+                    text.push('\n');
+                }
+            }
+            continue;
+        }
+
+        if let Some(b) = block.as_mut() {
+            let container = b.fence.container;
+            let (quotes, rest) = strip_quotes(line, container.quotes);
+            let blank = rest.trim().is_empty();
+
+            // Code blocks are implicitly closed when their container ends, in which case the line
+            // still has to be parsed as regular markdown
+            if quotes == container.quotes && (blank || indentation(rest) >= container.indent) {
+                let rest = strip_indent(rest, container.indent);
+                if is_closing(&rest, &b.fence) {
+                    events.extend(block.take().map(Event::Code));
+                } else {
+                    b.code.push_str(&strip_indent(&rest, b.fence.indent));
+                    b.code.push('\n');
+                }
+                continue;
+            }
+
+            events.extend(block.take().map(Event::Code));
+        }
+
+        let (quotes, rest) = strip_quotes(line, usize::MAX);
+        if quotes != quotes_prev {
+            lists.clear();
+            quotes_prev = quotes;
+        }
+
+        if rest.trim().is_empty() {
+            continue;
+        }
+
+        // Lines which are not indented enough close the list items they would otherwise belong to
+        let col = indentation(rest);
+        while lists.last().is_some_and(|&indent| col < indent) {
+            lists.pop();
+        }
+
+        let mut indent = lists.last().copied().unwrap_or_default();
+        let mut rest = strip_indent(rest, indent);
+        while let Some((marker, content)) = list_marker(&rest) {
+            indent += marker;
+            lists.push(indent);
+            rest = content;
+        }
+
+        let fence_indent = indentation(&rest);
+        if fence_indent > 3 {
+            continue;
+        }
+
+        let rest = rest.trim_start();
+        if let Some((char, len, info)) = opening_fence(rest) {
+            let container = Container { quotes, indent };
+            block = Some(Block {
+                line_number,
+                fence: Fence {
+                    char,
+                    len,
+                    indent: fence_indent,
+                    container,
+                },
+                info: info.to_string(),
+                code: String::new(),
+            });
+        } else if let Some(text) = rest.strip_prefix("<!--") {
+            match text.find("-->") {
+                Some(end) => events.push(Event::Comment {
+                    line_number,
+                    text: text[..end].to_string(),
+                }),
+                None => comment = Some((line_number, quotes, format!("{text}\n"))),
+            }
+        } else if let Some(text) = link_comment(rest) {
+            events.push(Event::Comment {
+                line_number,
+                text: text.to_string(),
+            });
+        }
+    }
+
+    if let Some((line_number, ..)) = comment {
+        return Err(Unclosed::Comment { line_number });
+    }
+
+    match block {
+        Some(block) => Err(Unclosed::Block {
+            line_number: block.line_number,
+            fence: block.fence,
+        }),
+        None => Ok(events),
+    }
+}
+
+/// Matches a link reference definition used as a comment, such as `[//]: # (comment)` or
+/// `[//]: # "comment"`.
+fn link_comment(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix("[//]:")?.trim_start().strip_prefix('#')?.trim();
+    rest.strip_prefix('(')
+        .and_then(|rest| rest.strip_suffix(')'))
+        .or_else(|| rest.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')))
+}
+
+/// Strips up to `max` blockquote markers from the start of a line, returning how many were found.
+fn strip_quotes(mut line: &str, max: usize) -> (usize, &str) {
+    let mut quotes = 0;
+    while quotes < max {
+        let trimmed = line.trim_start_matches(' ');
+        if line.len() - trimmed.len() > 3 {
+            break;
+        }
+        let Some(rest) = trimmed.strip_prefix('>') else {
+            break;
+        };
+        line = rest.strip_prefix(' ').unwrap_or(rest);
+        quotes += 1;
+    }
+    (quotes, line)
+}
+
+/// Width of the leading whitespace of a line, with tabs stopping at multiples of 4.
+fn indentation(line: &str) -> usize {
+    let mut col = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => col += 1,
+            '\t' => col += 4 - col % 4,
+            _ => break,
+        }
+    }
+    col
+}
+
+/// Removes up to `n` columns of leading whitespace from a line.
+fn strip_indent(line: &str, n: usize) -> String {
+    let mut col = 0;
+    for (i, c) in line.char_indices() {
+        if col >= n {
+            return line[i..].to_string();
+        }
+        match c {
+            ' ' => col += 1,
+            '\t' => {
+                let next = col + 4 - col % 4;
+                // Tabs which straddle the indentation are partially replaced with spaces
+                if next > n {
+                    return " ".repeat(next - n) + &line[i + 1..];
+                }
+                col = next;
+            }
+            _ => return line[i..].to_string(),
+        }
+    }
+    String::new()
+}
+
+/// Matches a list item marker such as `-`, `*`, `+`, `1.` or `1)`, returning the width of the
+/// marker (including its surrounding whitespace) and the content of the list item.
+fn list_marker(line: &str) -> Option<(usize, String)> {
+    let indent = indentation(line);
+    if indent > 3 {
+        return None;
+    }
+
+    let trimmed = line.trim_start();
+    let digits = trimmed.find(|c: char| !c.is_ascii_digit()).unwrap_or(trimmed.len());
+    let marker = match trimmed[digits..].chars().next()? {
+        '-' | '*' | '+' if digits == 0 => 1,
+        '.' | ')' if (1..=9).contains(&digits) => digits + 1,
+        _ => return None,
+    };
+
+    // List items must be followed by whitespace and cannot be empty
+    let content = &trimmed[marker..];
+    let spaces = indentation(content);
+    if spaces == 0 || content.trim().is_empty() {
+        return None;
+    }
+
+    // Content indented by 5 spaces or more is an indented code block starting after a single space
+    let spaces = if spaces > 4 { 1 } else { spaces };
+
+    Some((indent + marker + spaces, strip_indent(content, spaces)))
+}
+
+/// Matches an opening fence, returning its character, length and info string.
+fn opening_fence(line: &str) -> Option<(char, usize, &str)> {
+    let char = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.len() - line.trim_start_matches(char).len();
+    let info = line[len..].trim();
+
+    // Backtick fences cannot have backticks in their info string, as this would be inline code
+    if len < 3 || char == '`' && info.contains('`') {
+        return None;
+    }
+
+    Some((char, len, info))
+}
+
+fn is_closing(line: &str, fence: &Fence) -> bool {
+    if indentation(line) > 3 {
+        return false;
+    }
+
+    let line = line.trim_start();
+    let len = line.len() - line.trim_start_matches(fence.char).len();
+    len >= fence.len && line[len..].trim().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Line number, info string and code of each code block.
+    fn blocks(content: &str) -> Vec<(usize, String, String)> {
+        let Ok(events) = parse(content) else {
+            panic!("Unexpected unclosed element in {content:?}");
+        };
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Code(block) => Some((block.line_number, block.info, block.code)),
+                Event::Comment { .. } => None,
+            })
+            .collect()
+    }
+
+    fn block(line_number: usize, info: &str, code: &str) -> (usize, String, String) {
+        (line_number, info.to_string(), code.to_string())
+    }
+
+    #[test]
+    fn fences() {
+        assert_eq!(blocks("```console\n$ ls\n```\n"), [block(1, "console", "$ ls\n")]);
+        assert_eq!(blocks("~~~ console\n$ ls\n~~~\n"), [block(1, "console", "$ ls\n")]);
+        assert_eq!(blocks("```\n```\n"), [block(1, "", "")]);
+        assert!(blocks("``\ncode\n``\n").is_empty());
+    }
+
+    #[test]
+    fn closing_fences() {
+        // Fences are only closed by the same character
+        assert_eq!(blocks("```\n~~~\n```\n"), [block(1, "", "~~~\n")]);
+        assert_eq!(blocks("~~~\n```\n~~~\n"), [block(1, "", "```\n")]);
+
+        // Closing fences can be longer but not shorter, and cannot have an info string
+        assert_eq!(blocks("```\ncode\n`````\n"), [block(1, "", "code\n")]);
+        assert_eq!(blocks("````\n```\n````\n"), [block(1, "", "```\n")]);
+        assert_eq!(blocks("```\n``` sh\n```\n"), [block(1, "", "``` sh\n")]);
+
+        let fence = Fence {
+            char: '`',
+            len: 3,
+            indent: 0,
+            container: Container::default(),
+        };
+        assert!(is_closing("```", &fence));
+        assert!(is_closing("   ````  ", &fence));
+        assert!(!is_closing("    ```", &fence));
+        assert!(!is_closing("``", &fence));
+    }
+
+    #[test]
+    fn info_strings() {
+        assert_eq!(opening_fence("``` sh ignore "), Some(('`', 3, "sh ignore")));
+        assert_eq!(opening_fence("~~~~sh"), Some(('~', 4, "sh")));
+        assert_eq!(opening_fence("~~~ sh `quoted`"), Some(('~', 3, "sh `quoted`")));
+        assert_eq!(opening_fence("``` sh `quoted`"), None);
+        assert_eq!(opening_fence("``"), None);
+        assert_eq!(opening_fence("sh"), None);
+    }
+
+    #[test]
+    fn indentation() {
+        // Up to three spaces are removed from the code of indented fences
+        assert_eq!(
+            blocks("  ```\n  code\n    more\n```\n"),
+            [block(1, "", "code\n  more\n")]
+        );
+        assert!(blocks("    ```\n    code\n    ```\n").is_empty());
+
+        assert_eq!(strip_indent("    code", 2), "  code");
+        assert_eq!(strip_indent("\tcode", 2), "  code");
+        assert_eq!(strip_indent("\tcode", 4), "code");
+        assert_eq!(strip_indent("  ", 4), "");
+    }
+
+    #[test]
+    fn lists() {
+        let content = "1. Run:\n\n   ```sh\n   ls\n     -a\n   ```\n- item\n";
+        assert_eq!(blocks(content), [block(3, "sh", "ls\n  -a\n")]);
+
+        // Code blocks are closed at the end of their list item
+        let content = "- item\n\n  ```sh\n  ls\n\nafter\n```\n```\n";
+        assert_eq!(blocks(content), [block(3, "sh", "ls\n\n"), block(7, "", "")]);
+
+        assert_eq!(list_marker("- item"), Some((2, "item".to_string())));
+        assert_eq!(list_marker("  10.  item"), Some((7, "item".to_string())));
+        assert_eq!(list_marker("1) ```sh"), Some((3, "```sh".to_string())));
+        assert_eq!(list_marker("-      code"), Some((2, "     code".to_string())));
+        assert_eq!(list_marker("-item"), None);
+        assert_eq!(list_marker("- "), None);
+        assert_eq!(list_marker("1234567890. item"), None);
+    }
+
+    #[test]
+    fn blockquotes() {
+        let content = "> ```sh\n> ls\n>\n> ```\n";
+        assert_eq!(blocks(content), [block(1, "sh", "ls\n\n")]);
+        assert_eq!(blocks("> ```sh\n> ls\nafter\n"), [block(1, "sh", "ls\n")]);
+    }
+
+    #[test]
+    fn comments() {
+        let content = "<!-- one -->\n[//]: # (two)\n<!--\nthree\n-->\n```\n<!-- code -->\n```\n";
+        let comments = parse(content)
+            .ok()
+            .unwrap()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Comment { line_number, text } => Some((line_number, text)),
+                Event::Code(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            comments,
+            [(1, " one "), (2, "two"), (3, "\nthree\n")].map(|(n, text)| (n, text.to_string()))
+        );
+    }
+
+    #[test]
+    fn unclosed() {
+        match parse("text\n~~~~sh\nls\n~~~\n") {
+            Err(Unclosed::Block { line_number, fence }) => {
+                assert_eq!((line_number, fence.to_string()), (2, "~~~~".to_string()))
+            }
+            _ => panic!("Expected an unclosed code block"),
+        }
+        match parse("<!--\n```\n```\n") {
+            Err(Unclosed::Comment { line_number }) => assert_eq!(line_number, 1),
+            _ => panic!("Expected an unclosed comment"),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::summary::{Case, Failure, Outcome, Summary};
+
+/// Writes the results of a run as a JUnit XML report, for use in CI.
+///
+/// Each file is a test suite and each code block a test case named after its file and line. Errors
+/// which aborted a file are reported as a test case with an `<error>`.
+pub fn junit(out: &mut impl std::io::Write, summary: &Summary) -> std::io::Result<()> {
+    let aborted = summary.aborted();
+    let failed = summary.failures.len() - aborted.clone().count();
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuites name="md-command-check" tests="{}" failures="{failed}" errors="{}" skipped="{}" time="{:.3}">"#,
+        summary.reported(),
+        aborted.clone().count(),
+        summary.ignored,
+        summary.start.elapsed().as_secs_f64()
+    )?;
+
+    // Cases and errors are recorded in the same order as files
+    let mut cases = summary.cases.iter().peekable();
+    let mut aborted = aborted.peekable();
+
+    for (file_name, elapsed) in summary.files.iter() {
+        let mut file_cases = Vec::new();
+        while let Some(case) = cases.next_if(|case| case.file_name == *file_name) {
+            file_cases.push(case);
+        }
+        let file_error = aborted.next_if(|failure| failure.file_name == *file_name);
+
+        let failed = file_cases
+            .iter()
+            .filter(|case| matches!(case.outcome, Outcome::Failed(_)))
+            .count();
+        let ignored = file_cases
+            .iter()
+            .filter(|case| matches!(case.outcome, Outcome::Ignored))
+            .count();
+        let errors = file_error.is_some() as usize;
+
+        writeln!(
+            out,
+            r#"  <testsuite name="{}" tests="{}" failures="{failed}" errors="{errors}" skipped="{ignored}" time="{:.3}">"#,
+            escape(file_name),
+            file_cases.len() + errors,
+            elapsed.as_secs_f64()
+        )?;
+
+        for case in file_cases {
+            writeln!(
+                out,
+                r#"    <testcase name="{}:{}" classname="{}" time="{:.3}">"#,
+                escape(&case.file_name),
+                case.line_number,
+                escape(&case.file_name),
+                case.duration.as_secs_f64()
+            )?;
+
+            match &case.outcome {
+                Outcome::Passed => {}
+                Outcome::Failed(message) => writeln!(
+                    out,
+                    r#"      <failure message="{}">{}</failure>"#,
+                    escape(message.lines().next().unwrap_or_default()),
+                    escape(message)
+                )?,
+                Outcome::Ignored => writeln!(out, "      <skipped/>")?,
+            }
+
+            if !case.stdout.is_empty() {
+                writeln!(out, "      <system-out>{}</system-out>", escape(&case.stdout))?;
+            }
+            if !case.stderr.is_empty() {
+                writeln!(out, "      <system-err>{}</system-err>", escape(&case.stderr))?;
+            }
+
+            writeln!(out, "    </testcase>")?;
+        }
+
+        if let Some(failure) = file_error {
+            let (name, message) = match failure.line_number {
+                // Files which could not be read at all have no line to point to
+                0 => (escape(file_name), "File aborted".to_string()),
+                line_number => (
+                    format!("{}:{line_number}", escape(file_name)),
+                    format!("File aborted at line {line_number}"),
+                ),
+            };
+            let message = match &failure.message {
+                Some(error) => escape(&format!("File aborted: {error}")),
+                None => message,
+            };
+            writeln!(
+                out,
+                r#"    <testcase name="{name}" classname="{}" time="0.000">"#,
+                escape(file_name)
+            )?;
+            writeln!(out, r#"      <error message="{message}"/>"#)?;
+            writeln!(out, "    </testcase>")?;
+        }
+
+        writeln!(out, "  </testsuite>")?;
+    }
+
+    writeln!(out, "</testsuites>")?;
+    out.flush()
+}
+
+/// Writes the TAP test point for a code block, followed by YAML diagnostics if it failed.
+///
+/// Test points are numbered from 1 in the order in which they are reported. Ignored code blocks
+/// are reported with a `# SKIP` directive.
+pub fn tap(out: &mut impl std::io::Write, number: usize, case: &Case) -> std::io::Result<()> {
+    let name = format!("{}:{}", case.file_name, case.line_number).replace('#', "\\#");
+    match &case.outcome {
+        Outcome::Passed => writeln!(out, "ok {number} - {name}")?,
+        Outcome::Ignored => writeln!(out, "ok {number} - {name} # SKIP")?,
+        Outcome::Failed(message) => {
+            writeln!(out, "not ok {number} - {name}")?;
+            writeln!(out, "  ---")?;
+            yaml_string(out, "message", message)?;
+            yaml_string(out, "script", &case.script)?;
+            if let Some(code) = case.exit_code {
+                writeln!(out, "  exit_code: {code}")?;
+            }
+            yaml_string(out, "stdout", tail(&case.stdout))?;
+            yaml_string(out, "stderr", tail(&case.stderr))?;
+            writeln!(out, "  ...")?;
+        }
+    }
+    out.flush()
+}
+
+/// Writes the TAP test point for an error which aborted a file.
+pub fn tap_abort(out: &mut impl std::io::Write, number: usize, failure: &Failure) -> std::io::Result<()> {
+    let name = match failure.line_number {
+        0 => failure.file_name.replace('#', "\\#"),
+        line_number => format!("{}:{line_number}", failure.file_name).replace('#', "\\#"),
+    };
+    writeln!(out, "not ok {number} - {name}")?;
+    writeln!(out, "  ---")?;
+    match &failure.message {
+        Some(message) => yaml_string(out, "message", &format!("File aborted: {message}"))?,
+        None => writeln!(out, "  message: File aborted")?,
+    }
+    writeln!(out, "  ...")?;
+    out.flush()
+}
+
+/// Writes the TAP plan, once every test point has been reported.
+pub fn tap_plan(out: &mut impl std::io::Write, count: usize) -> std::io::Result<()> {
+    writeln!(out, "1..{count}")?;
+    out.flush()
+}
+
+/// Writes text as a double-quoted YAML string, which both YAML and the YAMLish subset understood by
+/// TAP parsers can read. Empty text is skipped.
+fn yaml_string(out: &mut impl std::io::Write, key: &str, text: &str) -> std::io::Result<()> {
+    let text = text.trim_end();
+    if text.is_empty() {
+        return Ok(());
+    }
+
+    let mut escaped = String::with_capacity(text.len() + 2);
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    writeln!(out, "  {key}: \"{escaped}\"")
+}
+
+/// Last lines of the output of a code block.
+fn tail(output: &str) -> &str {
+    const LINES: usize = 10;
+
+    let output = output.trim_end();
+    match output.rmatch_indices('\n').nth(LINES - 1) {
+        Some((n, _)) => &output[n + 1..],
+        None => output,
+    }
+}
+
+/// Escapes text for use in XML, dropping any control characters which XML does not allow.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,65 @@
+//! Registry of the languages which are run, along with how their code blocks are run.
+
+/// How the code blocks of a language are run.
+#[derive(Clone)]
+pub struct Runner {
+    pub language: String,
+    /// Program of the shell for sessions, command which scripts are passed to, such as
+    /// `python3 -u`, or cargo command for Rust.
+    pub command: String,
+    pub kind: Kind,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Code blocks are run in a long-running shell, sharing its variables and working directory.
+    Session,
+    /// Code blocks are run as a fresh script each time.
+    Script,
+    /// Code blocks are built and run as a scratch cargo project each time.
+    Cargo,
+}
+
+/// Shells which keep a session running from one code block to the next.
+const SESSIONS: &[(&str, &str)] = &[("sh", "sh"), ("bash", "bash"), ("zsh", "zsh")];
+
+/// Interpreters which run every code block as a fresh script.
+const SCRIPTS: &[(&str, &str)] = &[
+    ("python", "python3"),
+    ("py", "python3"),
+    ("javascript", "node"),
+    ("js", "node"),
+    ("ruby", "ruby"),
+    ("perl", "perl"),
+];
+
+/// Cargo commands which build and run code blocks in the same way as doctests.
+const CARGO: &[(&str, &str)] = &[("rust", "cargo")];
+
+pub fn defaults() -> Vec<Runner> {
+    let sessions = SESSIONS.iter().map(|runner| (runner, Kind::Session));
+    let scripts = SCRIPTS.iter().map(|runner| (runner, Kind::Script));
+    let cargo = CARGO.iter().map(|runner| (runner, Kind::Cargo));
+    sessions
+        .chain(scripts)
+        .chain(cargo)
+        .map(|((language, command), kind)| Runner {
+            language: language.to_string(),
+            command: command.to_string(),
+            kind,
+        })
+        .collect()
+}
+
+/// Registers a runner, replacing any runner for the same language. Runners without a command only
+/// remove the language, so that its code blocks are ignored.
+pub fn register(runners: &mut Vec<Runner>, runner: Runner) {
+    runners.retain(|known| known.language != runner.language);
+    if !runner.command.trim().is_empty() {
+        runners.push(runner);
+    }
+}
+
+pub fn find<'a>(runners: &'a [Runner], language: &str) -> Option<&'a Runner> {
+    runners.iter().find(|runner| runner.language == language)
+}
@@ -0,0 +1,154 @@
+//! Builds and runs `rust` code blocks as scratch cargo projects, in the same way as doctests.
+//!
+//! Code blocks without a `main` function are wrapped in one, and lines hidden from doctests with a
+//! leading `# ` are kept. Builds are offline, so dependencies have to be local paths or vendored.
+
+use crate::shell::{quote, temp_path};
+
+const NAME: &str = "md-command-check-block";
+
+/// How a code block is checked, following the attributes of doctests.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Check {
+    Run,
+    /// The code block is only built.
+    NoRun,
+    /// The code block has to panic when run.
+    ShouldPanic,
+    /// The code block has to fail to build.
+    CompileFail,
+}
+
+pub enum Dep {
+    Path { name: String, path: std::path::PathBuf },
+    Version { name: String, version: String },
+}
+
+/// Directory which every code block is built into, so that dependencies are only built once. It is
+/// removed once dropped, unless it was set with `CARGO_TARGET_DIR`.
+pub struct Target {
+    path: std::path::PathBuf,
+    temporary: bool,
+}
+
+impl Target {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(match std::env::var_os("CARGO_TARGET_DIR") {
+            Some(path) => Self {
+                path: path.into(),
+                temporary: false,
+            },
+            None => Self {
+                path: temp_path("target")?,
+                temporary: true,
+            },
+        })
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Scratch cargo project holding a single code block. It is removed once dropped.
+pub struct Project {
+    dir: std::path::PathBuf,
+    /// Name of the package, which is unique so that projects built into the same target directory,
+    /// possibly at the same time, do not overwrite each other's binary.
+    name: String,
+}
+
+impl Project {
+    pub fn new(code: &str, deps: &[Dep]) -> std::io::Result<Self> {
+        // Other runs can share the same target directory
+        let name = format!("{}-{}", name(), std::process::id());
+
+        let dir = temp_path("rust")?;
+        std::fs::create_dir_all(dir.join("src"))?;
+        let project = Self { dir, name };
+        std::fs::write(project.dir.join("Cargo.toml"), manifest(&project.name, deps))?;
+        std::fs::write(project.dir.join("src").join("main.rs"), source(code))?;
+        Ok(project)
+    }
+
+    /// Command building the project with `cargo`, then running it unless told otherwise.
+    pub fn command(&self, cargo: &str, target: &Target, check: Check) -> String {
+        let manifest = quote(&self.dir.join("Cargo.toml").to_string_lossy());
+        let target = quote(&target.path.to_string_lossy());
+        command(cargo, &self.name, &manifest, &target, check)
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Name of the package of a scratch project, numbered so that every project has its own binary.
+pub fn name() -> String {
+    static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let n = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("{NAME}-{n}")
+}
+
+/// Shell command building a project named `name`, then running it unless told otherwise. Paths are
+/// already quoted, so that they can be shell variables.
+pub fn command(cargo: &str, name: &str, manifest: &str, target: &str, check: Check) -> String {
+    let build = format!("{cargo} build --quiet --offline --manifest-path {manifest} --target-dir {target}");
+    let bin = format!("{target}/debug/{name}");
+    match check {
+        Check::Run => format!("{build} && {bin}"),
+        Check::NoRun => build,
+        // Panics exit with code 101
+        Check::ShouldPanic => format!("{build} && {{ {bin}; [ $? -eq 101 ]; }}"),
+        Check::CompileFail => format!("! {build}"),
+    }
+}
+
+/// `Cargo.toml` of a scratch project. It is its own workspace so that it is not mistaken for part
+/// of another one.
+pub fn manifest(name: &str, deps: &[Dep]) -> String {
+    let mut manifest = format!("[package]\nname = \"{name}\"\nversion = \"0.0.0\"\nedition = \"2021\"\n\n");
+    manifest.push_str("[dependencies]\n");
+    for dep in deps {
+        match dep {
+            Dep::Path { name, path } => {
+                let path = toml_string(&path.to_string_lossy());
+                manifest.push_str(&format!("{name} = {{ path = {path} }}\n"));
+            }
+            Dep::Version { name, version } => {
+                manifest.push_str(&format!("{name} = {}\n", toml_string(version)));
+            }
+        }
+    }
+    manifest.push_str("\n[workspace]\n");
+    manifest
+}
+
+/// `main.rs` of a scratch project.
+pub fn source(code: &str) -> String {
+    let code = code
+        .lines()
+        .map(|line| match line.strip_prefix("# ") {
+            Some(line) => line,
+            None if line == "#" => "",
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if code.contains("fn main") {
+        format!("#![allow(unused)]\n{code}\n")
+    } else {
+        format!("#![allow(unused)]\nfn main() {{\n{code}\n}}\n")
+    }
+}
+
+fn toml_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
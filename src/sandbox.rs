@@ -0,0 +1,69 @@
+//! Temporary workspace which code blocks are run in, so that commands such as `cargo new` or
+//! `rm -rf` do not touch the checkout. Paths can be copied into it beforehand.
+
+use crate::shell::private_dir;
+
+/// Directory holding the workspaces of a run. It is removed once dropped, unless it is kept.
+pub struct Sandbox {
+    pub path: std::path::PathBuf,
+    pub keep: bool,
+}
+
+impl Sandbox {
+    pub fn new(keep: bool) -> std::io::Result<Self> {
+        // The sandbox is not among the temporary files of the run, since it can be kept
+        let path = private_dir("sandbox")?;
+        Ok(Self { path, keep })
+    }
+
+    /// Creates a workspace holding a copy of every seed path, under its file name. Returns the
+    /// first path which could not be copied.
+    pub fn workspace(&self, name: Option<&str>, seed: &[String]) -> Result<std::path::PathBuf, String> {
+        let dir = match name {
+            Some(name) => self.path.join(name),
+            None => self.path.clone(),
+        };
+        std::fs::create_dir_all(&dir).map_err(|_| dir.to_string_lossy().to_string())?;
+
+        for path in seed {
+            let from = std::path::Path::new(path);
+            // Paths such as `.` or `..` are named after the directory they point to
+            let Some(name) = std::fs::canonicalize(from)
+                .ok()
+                .and_then(|from| from.file_name().map(|name| name.to_owned()))
+            else {
+                return Err(path.clone());
+            };
+            if copy(from, &dir.join(name)).is_err() {
+                return Err(path.clone());
+            }
+        }
+
+        Ok(dir)
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Copies a file or directory recursively, keeping symbolic links as they are.
+fn copy(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    let meta = std::fs::symlink_metadata(from)?;
+    if meta.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+    } else if meta.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        std::fs::copy(from, to).map(|_| ())
+    }
+}
@@ -0,0 +1,606 @@
+use std::io::Write as _;
+use std::os::fd::{AsRawFd as _, FromRawFd as _};
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::process::CommandExt as _;
+
+/// Output read from one of the streams of a [`Shell`].
+pub enum Chunk {
+    Stdout(String),
+    Stderr(String),
+}
+
+/// How a [`Shell`] is connected to its input and output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// `stdin`, `stdout` and `stderr` are pipes.
+    Pipe,
+    /// `stdin`, `stdout` and `stderr` are a pseudo-terminal, so that commands behave as they would
+    /// when run by a user. `stdout` and `stderr` cannot be told apart in this mode.
+    Pty,
+}
+
+/// Long-running shell process. Commands are fed to it through a pipe, while its output is read from
+/// background threads so that it can be waited on with a deadline.
+///
+/// The shell is started in its own process group so that it can be killed along with every process
+/// it has spawned.
+pub struct Shell {
+    mode: Mode,
+    child: std::process::Child,
+    stdin: Box<dyn std::io::Write + Send>,
+    chunks: std::sync::mpsc::Receiver<Chunk>,
+    /// Master end of the pseudo-terminal, in pty mode.
+    tty: Option<std::fs::File>,
+}
+
+impl Shell {
+    /// Spawns a shell running `program`, which may be followed by arguments, with additional
+    /// environment variables. The shell starts in `dir` if set, or in the current directory.
+    pub fn spawn(
+        mode: Mode,
+        program: &str,
+        env: &[(String, String)],
+        dir: Option<&std::path::Path>,
+    ) -> std::io::Result<Self> {
+        let mut words = program.split_whitespace();
+        let mut cmd = std::process::Command::new(words.next().unwrap_or("sh"));
+        cmd.args(words);
+        cmd.envs(env.iter().map(|(name, value)| (name, value)));
+        if let Some(dir) = dir {
+            cmd.current_dir(dir);
+        }
+        match mode {
+            Mode::Pipe => Self::spawn_pipe(cmd),
+            Mode::Pty => Self::spawn_pty(cmd),
+        }
+    }
+
+    fn spawn_pipe(mut cmd: std::process::Command) -> std::io::Result<Self> {
+        let mut child = cmd
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+
+        let stdin = Box::new(child.stdin.take().unwrap());
+        let (tx, chunks) = std::sync::mpsc::channel();
+        read_chunks(child.stdout.take().unwrap(), tx.clone(), Chunk::Stdout);
+        read_chunks(child.stderr.take().unwrap(), tx, Chunk::Stderr);
+
+        Ok(Self {
+            mode: Mode::Pipe,
+            child,
+            stdin,
+            chunks,
+            tty: None,
+        })
+    }
+
+    /// The shell reads its commands from a pipe on fd 3 rather than from the terminal: a shell
+    /// reading from a terminal would otherwise consider itself interactive and print prompts.
+    fn spawn_pty(mut cmd: std::process::Command) -> std::io::Result<Self> {
+        let (master, slave) = openpty()?;
+        let (script, stdin) = std::io::pipe()?;
+        let script_fd = script.as_raw_fd();
+
+        cmd.arg("/dev/fd/3")
+            .stdin(slave.try_clone()?)
+            .stdout(slave.try_clone()?)
+            .stderr(slave);
+
+        // SAFETY: only async-signal-safe functions are called between fork and exec
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                // `dup2` leaves the close-on-exec flag alone when both file descriptors are the same
+                let dup = match script_fd {
+                    3 => libc::fcntl(3, libc::F_SETFD, 0),
+                    _ => libc::dup2(script_fd, 3),
+                };
+                if dup == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let child = cmd.spawn()?;
+        drop(script);
+
+        let tty = master.try_clone()?;
+        let (tx, chunks) = std::sync::mpsc::channel();
+        read_chunks(master, tx, Chunk::Stdout);
+
+        let mut shell = Self {
+            mode: Mode::Pty,
+            child,
+            stdin: Box::new(stdin),
+            chunks,
+            tty: Some(tty),
+        };
+
+        // Commands should not inherit the script pipe
+        shell.write("exec 3<&-")?;
+
+        Ok(shell)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Opens a new channel through which input can be fed to the next code block.
+    pub fn input(&self) -> std::io::Result<Input> {
+        let (tx, rx) = std::sync::mpsc::channel::<String>();
+
+        let fifo = match self.tty.as_ref() {
+            Some(tty) => {
+                let tty = tty.try_clone()?;
+                write_input(rx, move || Ok(tty));
+                None
+            }
+            None => {
+                let fifo = temp_path("fifo")?;
+                let path = std::ffi::CString::new(fifo.as_os_str().as_encoded_bytes()).unwrap_or_default();
+
+                // SAFETY: `path` is a valid nul-terminated string
+                if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                // Opening a named pipe blocks until it is opened for reading by the code block
+                let path = fifo.clone();
+                write_input(rx, move || std::fs::OpenOptions::new().write(true).open(path));
+                Some(fifo)
+            }
+        };
+
+        Ok(Input {
+            tx: Some(tx),
+            fifo,
+            partial: std::cell::Cell::new(false),
+        })
+    }
+
+    /// Writes a raw command to the shell.
+    pub fn write(&mut self, cmd: &str) -> std::io::Result<()> {
+        writeln!(self.stdin, "{cmd}")
+    }
+
+    /// Runs a script, followed by markers on `stdout` and `stderr` which signal the end of its
+    /// execution. The marker on `stdout` also holds the exit code of the script.
+    pub fn run(&mut self, script: &str) -> std::io::Result<()> {
+        match self.mode {
+            Mode::Pipe => writeln!(
+                self.stdin,
+                "{}\nprintf '\\n%s:CMDEND\\n' \"$?\"; printf '\\n:CMDEND\\n' 1>&2",
+                script.trim_end()
+            ),
+            Mode::Pty => writeln!(self.stdin, "{}\nprintf '\\n%s:CMDEND\\n' \"$?\"", script.trim_end()),
+        }
+    }
+
+    /// Checks if the end markers of a script have been received.
+    pub fn is_done(&self, stdout: &str, stderr: &str) -> bool {
+        match self.mode {
+            Mode::Pipe => stdout.ends_with(":CMDEND\n") && stderr.ends_with("\n:CMDEND\n"),
+            Mode::Pty => stdout.ends_with(":CMDEND\n"),
+        }
+    }
+
+    /// Length of the output of a running script which can be shown, leaving out anything which might
+    /// be the start of an end marker.
+    pub fn visible(&self, output: &str) -> usize {
+        let Some(n) = output.rfind('\n') else {
+            return output.len();
+        };
+
+        // A complete end marker, along with the newline before it
+        let line = output[..n].trim_end_matches('\r');
+        if n + 1 == output.len() && line.ends_with(":CMDEND") {
+            return line.rfind('\n').unwrap_or_default();
+        }
+
+        // The start of an end marker
+        let tail = output[n + 1..].trim_start_matches(|c: char| c.is_ascii_digit() || c == '-');
+        if ":CMDEND".starts_with(tail) { n } else { output.len() }
+    }
+
+    /// Removes the end markers from the output of a script, returning its exit code.
+    pub fn strip_markers(&self, stdout: &mut String, stderr: &mut String) -> i32 {
+        let end = stdout.len() - ":CMDEND\n".len();
+        let start = stdout[..end].rfind('\n').unwrap_or_default();
+        let code = stdout[start..end].trim().parse().unwrap_or_default();
+
+        stdout.truncate(start);
+        if self.mode == Mode::Pipe {
+            stderr.truncate(stderr.len() - "\n:CMDEND\n".len());
+        }
+
+        code
+    }
+
+    /// Waits for the next chunk of output, up until an optional deadline.
+    pub fn recv(&self, deadline: Option<std::time::Instant>) -> Result<Chunk, std::sync::mpsc::RecvTimeoutError> {
+        match deadline {
+            Some(deadline) => self
+                .chunks
+                .recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())),
+            None => self
+                .chunks
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        }
+    }
+
+    pub fn wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
+        self.child.wait()
+    }
+
+    /// Kills the shell along with every process it has spawned.
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        // SAFETY: this only sends a signal to the process group of the shell
+        unsafe { libc::kill(-(self.child.id() as libc::pid_t), libc::SIGKILL) };
+        self.child.wait()?;
+        Ok(())
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        // SAFETY: this only sends a signal to the process group of the shell
+        unsafe { libc::kill(-(self.child.id() as libc::pid_t), libc::SIGKILL) };
+        let _ = self.child.try_wait();
+    }
+}
+
+/// Code block running in the background of a [`Shell`], as started by a `background` directive.
+///
+/// The output of the code block is redirected to a log file, and its exit code is written to a
+/// separate file once it terminates.
+pub struct Background {
+    pub line_number: usize,
+    pub pid: u32,
+    log: std::path::PathBuf,
+    code: std::path::PathBuf,
+}
+
+impl Background {
+    pub fn new(line_number: usize) -> std::io::Result<Self> {
+        let log = temp_path("log")?;
+        let code = log.with_extension("code");
+
+        Ok(Self {
+            line_number,
+            pid: 0,
+            log,
+            code,
+        })
+    }
+
+    /// Wraps a script so that it is run in the background. The pid of the background process is
+    /// written to `stdout`.
+    pub fn script(&self, script: &str) -> String {
+        format!(
+            "{{ (\n{}\n) >{} 2>&1 </dev/null; echo \"$?\" >{}; }} &\necho \"$!\"",
+            script.trim_end(),
+            quote(&self.log.to_string_lossy()),
+            quote(&self.code.to_string_lossy()),
+        )
+    }
+
+    pub fn output(&self) -> String {
+        std::fs::read(&self.log)
+            .map(|log| String::from_utf8_lossy(&log).into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Exit code of the background process, if it has terminated.
+    pub fn exit_code(&self) -> Option<i32> {
+        std::fs::read_to_string(&self.code).ok()?.trim().parse().ok()
+    }
+
+    /// Stops the background process along with every process it has spawned. Processes are given a
+    /// short grace period to exit after being sent `SIGTERM` before being killed.
+    pub fn stop(&self) -> std::io::Result<()> {
+        if self.pid == 0 {
+            return Ok(());
+        }
+
+        let tree = process_tree(self.pid)?;
+
+        for pid in tree.iter() {
+            // SAFETY: this only sends a signal to a process
+            unsafe { libc::kill(*pid as libc::pid_t, libc::SIGTERM) };
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        for pid in tree.iter() {
+            // SAFETY: this only sends a signal to a process
+            unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) };
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.log);
+        let _ = std::fs::remove_file(&self.code);
+    }
+}
+
+/// Code block written to a temporary file, so that it can be run by an interpreter from the shell.
+/// The file is removed once dropped.
+pub struct Script {
+    path: std::path::PathBuf,
+}
+
+impl Script {
+    pub fn new(code: &str, extension: &str) -> std::io::Result<Self> {
+        let path = temp_path(extension)?;
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(code.as_bytes())?;
+        Ok(Self { path })
+    }
+
+    /// Command running the script with an interpreter, such as `python3 -u`.
+    pub fn command(&self, interpreter: &str) -> String {
+        format!("{interpreter} {}", quote(&self.path.to_string_lossy()))
+    }
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Scripted input for a code block, as specified by `input` and `expect-prompt` directives.
+///
+/// In pipe mode, the code block reads its input from a named pipe, which is closed once all input
+/// has been written so that the code block receives an end of file. In pty mode, input is typed
+/// into the terminal instead, followed by ^D once done.
+pub struct Input {
+    tx: Option<std::sync::mpsc::Sender<String>>,
+    fifo: Option<std::path::PathBuf>,
+    /// Whether the input written so far stops in the middle of a line.
+    partial: std::cell::Cell<bool>,
+}
+
+impl Input {
+    /// Wraps a script so that it reads from this input.
+    pub fn redirect(&self, script: &str) -> String {
+        match self.fifo.as_ref() {
+            Some(fifo) => format!("{{\n{}\n}} <{}", script.trim_end(), quote(&fifo.to_string_lossy())),
+            None => script.to_string(),
+        }
+    }
+
+    pub fn write(&self, input: &str) {
+        if let Some(tx) = self.tx.as_ref()
+            && !input.is_empty()
+        {
+            self.partial.set(!input.ends_with('\n'));
+            let _ = tx.send(input.to_string());
+        }
+    }
+
+    /// Signals that no more input will be written.
+    pub fn close(&mut self) {
+        // Terminals only read ^D as an end of file at the start of a line, the first one otherwise
+        // ending the line
+        if self.fifo.is_none() {
+            self.write(if self.partial.get() { "\x04\x04" } else { "\x04" });
+        }
+        self.tx = None;
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(fifo) = self.fifo.take() {
+            // Unblocks the writer in case the code block never opened the named pipe
+            let _ = std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&fifo);
+            let _ = std::fs::remove_file(fifo);
+        }
+    }
+}
+
+fn write_input(
+    rx: std::sync::mpsc::Receiver<String>,
+    open: impl FnOnce() -> std::io::Result<std::fs::File> + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        let Ok(mut file) = open() else {
+            return;
+        };
+        for input in rx {
+            if file.write_all(input.as_bytes()).and_then(|_| file.flush()).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Private directory holding the temporary files of the run, created on first use.
+static TEMP_DIR: std::sync::Mutex<Option<std::path::PathBuf>> = std::sync::Mutex::new(None);
+
+/// Unique path to a temporary file, used to communicate with the shell. It lives in a directory
+/// which only the current user can access, so that other users cannot swap it for a symbolic link.
+pub fn temp_path(extension: &str) -> std::io::Result<std::path::PathBuf> {
+    static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let n = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    let mut dir = TEMP_DIR.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    if dir.is_none() {
+        *dir = Some(private_dir("tmp")?);
+    }
+    Ok(dir.as_ref().expect("Created above").join(format!("{n}.{extension}")))
+}
+
+/// Removes the temporary files of the run once dropped.
+pub struct TempFiles;
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        let dir = TEMP_DIR
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .take();
+        if let Some(dir) = dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Creates a new directory in the system's temporary directory, which only the current user can
+/// access. Existing directories are never reused, as someone else could have created them.
+pub fn private_dir(name: &str) -> std::io::Result<std::path::PathBuf> {
+    use std::os::unix::fs::DirBuilderExt as _;
+
+    for n in 0.. {
+        let path = std::env::temp_dir().join(format!("md-command-check-{}-{name}-{n}", std::process::id()));
+        match std::fs::DirBuilder::new().mode(0o700).create(&path) {
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|_| path),
+        }
+    }
+    unreachable!("Ran out of directory names")
+}
+
+/// Lists a process along with all of its descendants.
+fn process_tree(pid: u32) -> std::io::Result<Vec<u32>> {
+    let ps = std::process::Command::new("ps")
+        .args(["-A", "-o", "pid=", "-o", "ppid="])
+        .output()?;
+    let ps = String::from_utf8_lossy(&ps.stdout);
+
+    let processes = ps
+        .lines()
+        .filter_map(|line| {
+            let mut ids = line.split_whitespace().map(|id| id.parse::<u32>());
+            Some((ids.next()?.ok()?, ids.next()?.ok()?))
+        })
+        .collect::<Vec<_>>();
+
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            processes
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
+    }
+
+    Ok(tree)
+}
+
+/// Quotes a string so that it is interpreted literally by the shell.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Opens a new pseudo-terminal, returning its master and slave ends. Input is not echoed back and
+/// newlines are not translated so that output can be read the same as through a pipe.
+fn openpty() -> std::io::Result<(std::fs::File, std::fs::File)> {
+    let mut master = 0;
+    let mut slave = 0;
+    let winsize = libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // SAFETY: `openpty` initializes both file descriptors on success, which we then take ownership of
+    unsafe {
+        if libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &winsize,
+        ) == -1
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let master = std::fs::File::from_raw_fd(master);
+        let slave = std::fs::File::from_raw_fd(slave);
+
+        // Neither end should leak into other processes, the slave end being passed on explicitly
+        for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+            if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        termios.c_lflag &= !libc::ECHO;
+        termios.c_oflag &= !libc::ONLCR;
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok((master, slave))
+    }
+}
+
+/// Reads output from a stream as it becomes available, rather than line by line, so that prompts
+/// which do not end in a newline are received as well.
+fn read_chunks(
+    mut stream: impl std::io::Read + Send + 'static,
+    tx: std::sync::mpsc::Sender<Chunk>,
+    chunk: fn(String) -> Chunk,
+) {
+    std::thread::spawn(move || {
+        let mut buff = [0u8; 4096];
+        let mut pending = Vec::with_capacity(4096);
+        loop {
+            match stream.read(&mut buff) {
+                Ok(0) => break,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+                Ok(n) => {
+                    pending.extend_from_slice(&buff[..n]);
+
+                    // Multi-byte characters might be split across reads
+                    let valid = match std::str::from_utf8(&pending) {
+                        Ok(_) => pending.len(),
+                        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                        Err(_) => pending.len(),
+                    };
+
+                    let out = String::from_utf8_lossy(&pending[..valid]).into_owned();
+                    pending.drain(..valid);
+
+                    if !out.is_empty() && tx.send(chunk(out)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,109 @@
+/// Results of every code block run so far, reported at the end of the run.
+pub struct Summary {
+    pub start: std::time::Instant,
+    pub passed: usize,
+    pub ignored: usize,
+    pub files_created: usize,
+    /// Time spent running each file.
+    pub files: Vec<(String, std::time::Duration)>,
+    pub failures: Vec<Failure>,
+    /// Every code block which was either run or ignored, in order.
+    pub cases: Vec<Case>,
+    /// Line of the directive or code block currently being processed. Errors which abort a file are
+    /// reported at this line.
+    pub line_number: usize,
+    /// Directory which code blocks were run in, if running in a sandbox, along with whether it is
+    /// kept once done.
+    pub sandbox: Option<(String, bool)>,
+}
+
+/// A failing code block, or an error which caused a file to be aborted.
+pub struct Failure {
+    pub file_name: String,
+    pub line_number: usize,
+    /// Time spent running the code block, if it was run at all.
+    pub duration: Option<std::time::Duration>,
+    /// Error which aborted the file, if any.
+    pub message: Option<String>,
+}
+
+/// A code block which was either run or ignored.
+pub struct Case {
+    pub file_name: String,
+    pub line_number: usize,
+    pub duration: std::time::Duration,
+    pub script: String,
+    /// Exit code of the code block, if it ran to completion.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub outcome: Outcome,
+}
+
+pub enum Outcome {
+    Passed,
+    /// Failed with a message explaining why, the first line of which is a short description.
+    Failed(String),
+    Ignored,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+            passed: 0,
+            ignored: 0,
+            files_created: 0,
+            files: Vec::new(),
+            failures: Vec::new(),
+            cases: Vec::new(),
+            line_number: 0,
+            sandbox: None,
+        }
+    }
+
+    pub fn fail(&mut self, file_name: &str, line_number: usize, duration: Option<std::time::Duration>) {
+        self.failures.push(Failure {
+            file_name: file_name.to_string(),
+            line_number,
+            duration,
+            message: None,
+        });
+    }
+
+    /// Records the result of a code block.
+    pub fn record(&mut self, case: Case) {
+        match case.outcome {
+            Outcome::Passed => self.passed += 1,
+            Outcome::Failed(_) => self.fail(&case.file_name, case.line_number, Some(case.duration)),
+            Outcome::Ignored => self.ignored += 1,
+        }
+        self.cases.push(case);
+    }
+
+    /// Records an error which aborted a file, unless it was caused by a failure already recorded.
+    /// Returns whether the error was recorded.
+    pub fn abort(&mut self, file_name: &str, message: String) -> bool {
+        let recorded = self
+            .failures
+            .last()
+            .is_some_and(|failure| failure.file_name == file_name && failure.line_number == self.line_number);
+        if !recorded {
+            self.fail(file_name, self.line_number, None);
+            if let Some(failure) = self.failures.last_mut() {
+                failure.message = Some(message);
+            }
+        }
+        !recorded
+    }
+
+    /// Errors which aborted a file, as opposed to failing code blocks.
+    pub fn aborted(&self) -> impl Iterator<Item = &Failure> + Clone {
+        self.failures.iter().filter(|failure| failure.duration.is_none())
+    }
+
+    /// Number of code blocks and errors reported so far.
+    pub fn reported(&self) -> usize {
+        self.cases.len() + self.aborted().count()
+    }
+}
@@ -0,0 +1,200 @@
+//! Runs `md-command-check` on small Markdown files and checks the outcome of the run.
+
+/// Runs a Markdown file from a directory of its own, returning whether the run passed.
+fn run(name: &str, markdown: &str) -> bool {
+    let dir = std::env::temp_dir().join(format!("md-command-check-test-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("test.md"), markdown).unwrap();
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_md-command-check"))
+        .args(["--progress=plain", "--color=never", "test.md"])
+        .current_dir(&dir)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    status.success()
+}
+
+#[test]
+fn background_does_not_carry_over_an_ignored_block() {
+    let markdown = "\
+<!-- background -->
+<!-- ignore -->
+
+```bash
+sleep 10
+```
+
+```bash
+echo hi; exit 3
+```
+";
+    assert!(!run("background-ignore", markdown));
+}
+
+#[test]
+fn background_does_not_carry_over_a_block_in_another_language() {
+    let markdown = "\
+<!-- background -->
+
+```text
+sample output
+```
+
+```bash
+echo hi; exit 3
+```
+";
+    assert!(!run("background-text", markdown));
+}
+
+#[test]
+fn teardown_keeps_semicolons() {
+    let markdown = "\
+```bash
+true
+```
+
+<!-- teardown export A=1; export B=2 -->
+
+```bash
+[ \"$B\" = 2 ]
+```
+";
+    assert!(run("teardown-semicolon", markdown));
+}
+
+#[test]
+fn teardown_keeps_quotes() {
+    let markdown = "\
+```bash
+true
+```
+
+<!-- teardown sh -c 'echo \"a  b\" > out.txt' -->
+
+```bash
+[ \"$(cat out.txt)\" = \"a  b\" ]
+```
+";
+    assert!(run("teardown-quotes", markdown));
+}
+
+#[test]
+fn regular_comments_are_not_directives() {
+    let markdown = "\
+<!-- TODO: rewrite this section -->
+<!-- Don't \"quote\" me on this -->
+
+```bash
+true
+```
+";
+    assert!(run("comments", markdown));
+}
+
+#[test]
+fn prose_comments_close_to_directives_are_not_directives() {
+    let markdown = "\
+<!-- end of the install section -->
+<!-- will be rewritten -->
+<!-- time to clean up -->
+<!-- version 2 only -->
+<!-- export these variables first -->
+
+```bash
+true
+```
+";
+    assert!(run("prose", markdown));
+}
+
+#[test]
+fn typos_of_directives_are_reported() {
+    let markdown = "\
+<!-- igonre -->
+
+```bash
+true
+```
+";
+    assert!(!run("typo", markdown));
+}
+
+#[test]
+fn other_doctest_attributes_are_ignored() {
+    let markdown = "\
+```bash,edition2021
+true
+```
+";
+    assert!(run("doctest-attributes", markdown));
+}
+
+#[test]
+fn attributes_for_other_tools_are_ignored() {
+    let markdown = "\
+```bash {showLineNumbers .class #id title=\"run.sh\"}
+true
+```
+";
+    assert!(run("other-attributes", markdown));
+}
+
+#[test]
+fn pty_input_ends_with_an_end_of_file() {
+    let markdown = "\
+<!-- pty -->
+<!-- timeout 5 -->
+<!-- input \"a\\nb\" -->
+<!-- expect -->
+
+```bash
+cat
+```
+
+```text
+a
+b
+```
+";
+    assert!(run("pty-eof", markdown));
+}
+
+#[test]
+fn regular_comments_can_precede_the_expected_output() {
+    let markdown = "\
+<!-- expect -->
+
+```bash
+echo hi
+```
+
+<!-- the output is -->
+
+```text
+hi
+```
+";
+    assert!(run("expect-comment", markdown));
+}
+
+#[test]
+fn directives_are_not_skipped_looking_for_the_expected_output() {
+    let markdown = "\
+<!-- expect -->
+
+```bash
+echo hi
+```
+
+<!-- teardown true -->
+
+```text
+hi
+```
+";
+    assert!(!run("expect-directive", markdown));
+}